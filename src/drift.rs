@@ -0,0 +1,320 @@
+//! Correction of camera clocks that were off by a fixed amount, per device.
+//!
+//! Files are grouped by the camera body that took them. The offset of its
+//! clock is either given, or estimated from the files whose name carries a
+//! date as well.
+
+use crate::duration::format_duration;
+use crate::journal::{self, Entry, Target};
+use crate::resolver::{Decision, Resolution};
+use crate::source;
+use chrono::{DateTime, Duration, FixedOffset};
+use exif::{Exif, Tag};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Fewest files with both a filename and an EXIF date to estimate an offset
+pub const MIN_SAMPLES: usize = 3;
+
+/// A camera body, from its EXIF `Make`, `Model` and `BodySerialNumber`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Device {
+    pub make: String,
+    pub model: String,
+    pub serial: Option<String>,
+}
+
+impl Device {
+    /// The device that took a photo, if its EXIF data names the model
+    pub fn from_exif(exif: &Exif) -> Option<Self> {
+        Some(Device {
+            make: source::exif_text(exif, Tag::Make).unwrap_or_default(),
+            model: source::exif_model(exif)?,
+            serial: source::exif_text(exif, Tag::BodySerialNumber),
+        })
+    }
+
+    /// Name of the device without its serial number, e.g. "Canon EOS 5D"
+    pub fn name(&self) -> String {
+        if self.make.is_empty() || self.model.starts_with(&self.make) {
+            self.model.clone()
+        } else {
+            format!("{} {}", self.make, self.model)
+        }
+    }
+
+    /// Whether a user-given name designates this device: its full name
+    /// with the serial number, or its name for every body of the model
+    pub fn is_named(&self, name: &str) -> bool {
+        name == self.to_string() || name == self.name()
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.serial {
+            Some(ref serial) => write!(f, "{} #{}", self.name(), serial),
+            None => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// A file taken with a known device
+#[derive(Debug, Clone)]
+pub struct DeviceFile {
+    pub path: PathBuf,
+    pub device: Device,
+    /// The EXIF date of the file, as recorded by the device clock
+    pub exif: DateTime<FixedOffset>,
+    /// How far the filename date is ahead of the EXIF date, when the
+    /// filename has a date and time
+    pub difference: Option<Duration>,
+}
+
+/// Where the offset of a device clock comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Given,
+    /// Estimated from `samples` files, `agreeing` of them within the
+    /// tolerance of the offset
+    Estimated { samples: usize, agreeing: usize },
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Given => write!(f, "given"),
+            Origin::Estimated { samples, agreeing } => {
+                write!(f, "estimated from {} of {} files", agreeing, samples)
+            }
+        }
+    }
+}
+
+/// The amount to add to the dates of a device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Offset {
+    pub duration: Duration,
+    pub origin: Origin,
+}
+
+impl fmt::Display for Offset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", format_duration(self.duration), self.origin)
+    }
+}
+
+/// Estimate a clock offset as the median of the differences between
+/// filename and EXIF dates. Too few differences, or too few of them within
+/// the tolerance of the median, give no estimate.
+pub fn estimate(differences: &[Duration], tolerance: Duration) -> Option<Offset> {
+    if differences.len() < MIN_SAMPLES {
+        return None;
+    }
+    let mut sorted = differences.to_vec();
+    sorted.sort();
+    let median = sorted[sorted.len() / 2];
+    let agreeing = sorted
+        .iter()
+        .filter(|d| (**d - median).abs() <= tolerance)
+        .count();
+    if agreeing * 2 <= sorted.len() {
+        return None;
+    }
+    Some(Offset {
+        duration: median,
+        origin: Origin::Estimated {
+            samples: sorted.len(),
+            agreeing,
+        },
+    })
+}
+
+/// The offset of every device with one, given ones first. Estimated offsets
+/// within the tolerance are no drift at all and left out.
+pub fn offsets(
+    files: &[DeviceFile],
+    given: &[(String, Duration)],
+    tolerance: Duration,
+) -> BTreeMap<Device, Offset> {
+    let mut differences: BTreeMap<&Device, Vec<Duration>> = BTreeMap::new();
+    for file in files {
+        let entry = differences.entry(&file.device).or_default();
+        entry.extend(file.difference);
+    }
+    differences
+        .into_iter()
+        .filter_map(|(device, differences)| {
+            let offset = match given.iter().find(|(name, _)| device.is_named(name)) {
+                Some((_, duration)) => Offset {
+                    duration: *duration,
+                    origin: Origin::Given,
+                },
+                None => estimate(&differences, tolerance)
+                    .filter(|offset| offset.duration.abs() > tolerance)?,
+            };
+            Some((device.clone(), offset))
+        })
+        .collect()
+}
+
+/// Whether a given offset was already applied to a file: the journal
+/// records its current EXIF date as the result of shifting it by that
+/// offset. Estimated offsets are measured on the current dates, so they are
+/// never applied already.
+pub fn already_shifted(
+    entries: &[Entry],
+    file: &DeviceFile,
+    offset: &Offset,
+    tolerance: Duration,
+) -> bool {
+    let path = journal::absolute(&file.path);
+    offset.origin == Origin::Given
+        && entries.iter().any(|entry| {
+            entry.path == path
+                && entry.target == Target::Metadata
+                && entry.from.is_some_and(|from| entry.to - from == offset.duration)
+                && (entry.to - file.exif).abs() <= tolerance
+        })
+}
+
+/// The decision shifting the EXIF date of a file by the offset of its device
+pub fn decision(file: &DeviceFile, offset: &Offset) -> Decision {
+    Decision {
+        resolution: Resolution::WriteMetadata {
+            from: Some(file.exif),
+            to: file.exif + offset.duration,
+        },
+        confidence: 1.0,
+        reason: format!("clock offset of {}: {}", file.device, offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn minutes(values: &[i64]) -> Vec<Duration> {
+        values.iter().map(|m| Duration::minutes(*m)).collect()
+    }
+
+    fn device(serial: Option<&str>) -> Device {
+        Device {
+            make: "Canon".into(),
+            model: "Canon EOS 5D".into(),
+            serial: serial.map(String::from),
+        }
+    }
+
+    #[test]
+    fn estimates_the_median_offset() {
+        let one_minute = Duration::minutes(1);
+        let offset = estimate(&minutes(&[72, 73, 72, -5000, 72]), one_minute).unwrap();
+        assert_eq!(offset.duration, Duration::minutes(72));
+        assert_eq!(offset.origin, Origin::Estimated { samples: 5, agreeing: 4 });
+        assert_eq!(estimate(&minutes(&[72, 72]), one_minute), None);
+        assert_eq!(estimate(&minutes(&[72, 300, -60, 2000]), one_minute), None);
+    }
+
+    #[test]
+    fn matches_devices_by_name() {
+        let body = device(Some("1234"));
+        assert_eq!(body.to_string(), "Canon EOS 5D #1234");
+        assert!(body.is_named("Canon EOS 5D #1234"));
+        assert!(body.is_named("Canon EOS 5D"));
+        assert!(!body.is_named("Canon EOS 5D #999"));
+        let nikon = Device {
+            make: "NIKON CORPORATION".into(),
+            model: "NIKON D70".into(),
+            serial: None,
+        };
+        assert_eq!(nikon.to_string(), "NIKON CORPORATION NIKON D70");
+    }
+
+    #[test]
+    fn gives_precedence_to_given_offsets() {
+        let file = |device: Device, difference: Option<i64>| DeviceFile {
+            path: PathBuf::new(),
+            device,
+            exif: "2019-08-02T10:00:00+00:00".parse().unwrap(),
+            difference: difference.map(Duration::minutes),
+        };
+        let files = [
+            file(device(Some("1")), Some(60)),
+            file(device(Some("1")), Some(60)),
+            file(device(Some("1")), Some(61)),
+            file(device(Some("2")), Some(60)),
+            file(device(Some("2")), None),
+            file(device(None), Some(0)),
+            file(device(None), Some(0)),
+            file(device(None), Some(0)),
+        ];
+        let given = [("Canon EOS 5D #2".to_string(), Duration::hours(-2))];
+        let offsets = offsets(&files, &given, Duration::minutes(1));
+        assert_eq!(offsets.len(), 2);
+        assert_eq!(offsets[&device(Some("1"))].duration, Duration::minutes(60));
+        assert_eq!(offsets[&device(Some("2"))].origin, Origin::Given);
+    }
+
+    #[test]
+    fn finds_given_offsets_already_applied() {
+        let date = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+        let file = DeviceFile {
+            path: PathBuf::from("IMG_0001.JPG"),
+            device: device(None),
+            exif: date("2019-08-02T10:00:00+00:00"),
+            difference: None,
+        };
+        let given = Offset {
+            duration: Duration::hours(-2),
+            origin: Origin::Given,
+        };
+        let shifted = decision(&file, &given);
+        assert_eq!(
+            shifted.resolution,
+            Resolution::WriteMetadata {
+                from: Some(date("2019-08-02T10:00:00+00:00")),
+                to: date("2019-08-02T08:00:00+00:00"),
+            }
+        );
+        assert_eq!(shifted.reason, "clock offset of Canon EOS 5D: -2h (given)");
+
+        // The journal of a shift from 12:00 to the current date
+        let entry = |path: &str, target, from: &str| Entry {
+            path: journal::absolute(Path::new(path)),
+            target,
+            from: Some(date(from)),
+            to: date("2019-08-02T10:00:30+00:00"),
+            at: date("2024-01-01T00:00:00+00:00"),
+        };
+        let one_minute = Duration::minutes(1);
+        let entries = [entry("IMG_0001.JPG", Target::Metadata, "2019-08-02T12:00:30+00:00")];
+        assert!(already_shifted(&entries, &file, &given, one_minute));
+        let estimated = Offset {
+            origin: Origin::Estimated {
+                samples: 3,
+                agreeing: 3,
+            },
+            ..given
+        };
+        assert!(!already_shifted(&entries, &file, &estimated, one_minute));
+        let other = Offset {
+            duration: Duration::hours(-1),
+            ..given
+        };
+        assert!(!already_shifted(&entries, &file, &other, one_minute));
+        let moved = DeviceFile {
+            exif: date("2019-08-02T11:00:00+00:00"),
+            ..file.clone()
+        };
+        assert!(!already_shifted(&entries, &moved, &given, one_minute));
+        for entries in [
+            [entry("IMG_0002.JPG", Target::Metadata, "2019-08-02T12:00:30+00:00")],
+            [entry("IMG_0001.JPG", Target::FileTime, "2019-08-02T12:00:30+00:00")],
+        ] {
+            assert!(!already_shifted(&entries, &file, &given, one_minute));
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Durations written as `90s`, `5m`, `1h30m` or `-2d`.
+
+use chrono::Duration;
+
+/// Parse a duration made of whole numbers of days (`d`), hours (`h`),
+/// minutes (`m`) and seconds (`s`), optionally signed
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("Invalid duration '{}', expected e.g. 90s, 5m or 1h30m", text);
+    let (negative, rest) = match text.trim().strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.trim().trim_start_matches('+')),
+    };
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    let mut total = Duration::zero();
+    let mut digits = String::new();
+    for c in rest.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let value: i64 = digits.parse().map_err(|_| invalid())?;
+        let part = match c {
+            'd' => Duration::try_days(value),
+            'h' => Duration::try_hours(value),
+            'm' => Duration::try_minutes(value),
+            's' => Duration::try_seconds(value),
+            _ => None,
+        };
+        total = part.and_then(|p| total.checked_add(&p)).ok_or_else(invalid)?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return Err(invalid());
+    }
+    Ok(if negative { -total } else { total })
+}
+
+/// Format a duration the way `parse_duration` reads it
+pub fn format_duration(duration: Duration) -> String {
+    let sign = if duration < Duration::zero() { "-" } else { "" };
+    let mut seconds = duration.num_seconds().abs();
+    if seconds == 0 {
+        return "0s".to_string();
+    }
+    let mut out = sign.to_string();
+    for (unit, size) in [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)] {
+        if seconds >= size {
+            out.push_str(&format!("{}{}", seconds / size, unit));
+            seconds %= size;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_formats() {
+        let cases = [
+            ("90s", 90, "1m30s"),
+            ("5m", 300, "5m"),
+            ("1h30m", 5400, "1h30m"),
+            ("-2d", -172800, "-2d"),
+            ("+1d1s", 86401, "1d1s"),
+            ("0s", 0, "0s"),
+        ];
+        for (text, seconds, formatted) in cases {
+            let duration = parse_duration(text).unwrap();
+            assert_eq!(duration.num_seconds(), seconds, "{}", text);
+            assert_eq!(format_duration(duration), formatted, "{}", text);
+        }
+        for text in ["", "-", "5", "m", "1x", "1h-5m"] {
+            assert!(parse_duration(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+//! Locating and updating the Exif item of HEIF/HEIC files.
+//!
+//! The Exif item is found through the `iinf` and `iloc` boxes of the
+//! top-level `meta` box. Edits that keep the TIFF block the same size are
+//! written in place; otherwise the new block is appended in its own `mdat`
+//! box and the item location is updated, so the image data is never touched.
+
+use crate::tiff::TiffEditor;
+use chrono::{DateTime, FixedOffset};
+use std::ops::Range;
+
+struct IsoBox {
+    /// Offset of the box header
+    start: usize,
+    typ: [u8; 4],
+    content: Range<usize>,
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+}
+
+/// Read a big-endian number of `size` bytes at `*pos` and advance
+fn take(data: &[u8], pos: &mut usize, size: usize) -> Result<u64, String> {
+    let bytes = data.get(*pos..*pos + size).ok_or("Truncated HEIF box")?;
+    *pos += size;
+    Ok(be(bytes))
+}
+
+/// Check the size of an iloc field: ISO/IEC 14496-12 allows 0, 4 or 8 bytes
+fn field_size(size: usize) -> Result<usize, String> {
+    match size {
+        0 | 4 | 8 => Ok(size),
+        _ => Err(format!("Invalid iloc field size {}", size)),
+    }
+}
+
+fn boxes(data: &[u8], range: Range<usize>) -> Result<Vec<IsoBox>, String> {
+    let mut out = Vec::new();
+    let mut pos = range.start;
+    while pos + 8 <= range.end {
+        let size = be(&data[pos..pos + 4]) as usize;
+        let typ: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+        let (content, end) = match size {
+            0 => (pos + 8, range.end),
+            1 => {
+                let large = be(data.get(pos + 8..pos + 16).ok_or("Truncated HEIF box")?);
+                let end = usize::try_from(large).ok().and_then(|large| pos.checked_add(large));
+                (pos + 16, end.ok_or("Invalid HEIF box size")?)
+            }
+            _ => (pos + 8, pos + size),
+        };
+        if end < content || end > range.end {
+            return Err("Invalid HEIF box size".to_string());
+        }
+        out.push(IsoBox {
+            start: pos,
+            typ,
+            content: content..end,
+        });
+        pos = end;
+    }
+    Ok(out)
+}
+
+fn find<'a>(boxes: &'a [IsoBox], typ: &[u8; 4]) -> Option<&'a IsoBox> {
+    boxes.iter().find(|b| &b.typ == typ)
+}
+
+/// Where the Exif item is stored and where its location is recorded
+struct ExifItem {
+    /// The item data: a 4 byte TIFF header offset followed by the block
+    payload: Range<usize>,
+    tiff_start: usize,
+    base_offset: u64,
+    offset_pos: usize,
+    offset_size: usize,
+    length_pos: usize,
+    length_size: usize,
+}
+
+/// ID of the item of type `Exif` in the `iinf` box
+fn exif_item_id(data: &[u8], iinf: &IsoBox) -> Result<Option<u64>, String> {
+    let version = *data[iinf.content.clone()].first().ok_or("Truncated iinf box")?;
+    let start = iinf.content.start + 4 + if version == 0 { 2 } else { 4 };
+    for infe in boxes(data, start..iinf.content.end)? {
+        if &infe.typ != b"infe" {
+            continue;
+        }
+        let mut pos = infe.content.start;
+        let version = take(data, &mut pos, 1)?;
+        pos += 3;
+        if version < 2 {
+            continue;
+        }
+        let id = take(data, &mut pos, if version == 2 { 2 } else { 4 })?;
+        pos += 2;
+        if data.get(pos..pos + 4) == Some(b"Exif") {
+            return Ok(Some(id));
+        }
+    }
+    Ok(None)
+}
+
+fn locate_exif(data: &[u8]) -> Result<ExifItem, String> {
+    let top = boxes(data, 0..data.len())?;
+    match top.first() {
+        Some(b) if &b.typ == b"ftyp" => {}
+        _ => return Err("Not a HEIF file".to_string()),
+    }
+    let meta = find(&top, b"meta").ok_or("No meta box")?;
+    let children = boxes(data, meta.content.start + 4..meta.content.end)?;
+    let iinf = find(&children, b"iinf").ok_or("No iinf box")?;
+    let iloc = find(&children, b"iloc").ok_or("No iloc box")?;
+    let exif_id = exif_item_id(data, iinf)?.ok_or("No Exif item")?;
+
+    let mut pos = iloc.content.start;
+    let version = take(data, &mut pos, 1)?;
+    pos += 3;
+    let sizes = take(data, &mut pos, 1)? as usize;
+    let (offset_size, length_size) = (field_size(sizes >> 4)?, field_size(sizes & 0xF)?);
+    let sizes = take(data, &mut pos, 1)? as usize;
+    let base_offset_size = field_size(sizes >> 4)?;
+    let index_size = if version == 1 || version == 2 { field_size(sizes & 0xF)? } else { 0 };
+    let wide = version >= 2;
+    let item_count = take(data, &mut pos, if wide { 4 } else { 2 })?;
+    for _ in 0..item_count {
+        let id = take(data, &mut pos, if wide { 4 } else { 2 })?;
+        let construction_method = if version == 1 || version == 2 {
+            take(data, &mut pos, 2)? & 0xF
+        } else {
+            0
+        };
+        pos += 2;
+        let base_offset = take(data, &mut pos, base_offset_size)?;
+        let extent_count = take(data, &mut pos, 2)?;
+        let extents_start = pos;
+        pos += extent_count as usize * (index_size + offset_size + length_size);
+        if id != exif_id {
+            continue;
+        }
+        if construction_method != 0 || extent_count != 1 {
+            return Err("Unsupported Exif item location".to_string());
+        }
+        let offset_pos = extents_start + index_size;
+        let length_pos = offset_pos + offset_size;
+        let mut p = offset_pos;
+        let offset = take(data, &mut p, offset_size)?;
+        let length = take(data, &mut p, length_size)?;
+        let out_of_range = || "Exif item out of range".to_string();
+        let start = base_offset
+            .checked_add(offset)
+            .and_then(|start| usize::try_from(start).ok())
+            .ok_or_else(out_of_range)?;
+        let end = usize::try_from(length)
+            .ok()
+            .and_then(|length| start.checked_add(length))
+            .ok_or_else(out_of_range)?;
+        let mut p = start;
+        let tiff_offset = take(data, &mut p, 4)? as usize;
+        let tiff_start = p.checked_add(tiff_offset).ok_or_else(out_of_range)?;
+        if end > data.len() || tiff_start >= end {
+            return Err(out_of_range());
+        }
+        return Ok(ExifItem {
+            payload: start..end,
+            tiff_start,
+            base_offset,
+            offset_pos,
+            offset_size,
+            length_pos,
+            length_size,
+        });
+    }
+    Err("Exif item has no location".to_string())
+}
+
+/// Return the TIFF block of the Exif item
+pub fn exif_tiff(data: &[u8]) -> Result<&[u8], String> {
+    let item = locate_exif(data)?;
+    Ok(&data[item.tiff_start..item.payload.end])
+}
+
+/// Write a big-endian number into a field of `size` bytes
+fn put(data: &mut [u8], pos: usize, size: usize, value: u64) -> Result<(), String> {
+    if field_size(size)? == 0 || (size == 4 && value > u64::from(u32::MAX)) {
+        return Err("Exif item location does not fit".to_string());
+    }
+    let bytes = value.to_be_bytes();
+    data[pos..pos + size].copy_from_slice(&bytes[8 - size..]);
+    Ok(())
+}
+
+/// Set the EXIF dates of a HEIF file held in memory
+pub fn write_dates(data: &[u8], date: DateTime<FixedOffset>) -> Result<Vec<u8>, String> {
+    let item = locate_exif(data)?;
+    let old_tiff = &data[item.tiff_start..item.payload.end];
+    let mut editor = TiffEditor::new(old_tiff.to_vec())?;
+    editor.set_dates(date)?;
+    let tiff = editor.into_bytes();
+    let mut out = data.to_vec();
+    if tiff.len() == old_tiff.len() {
+        out[item.tiff_start..item.payload.end].copy_from_slice(&tiff);
+        return Ok(out);
+    }
+    // Keep the TIFF header offset and any "Exif\0\0" prefix of the item
+    let mut payload = data[item.payload.start..item.tiff_start].to_vec();
+    payload.extend_from_slice(&tiff);
+    let top = boxes(data, 0..data.len())?;
+    let last = top.last().ok_or("Not a HEIF file")?;
+    if last.content.end != data.len() {
+        return Err("HEIF file has trailing data".to_string());
+    }
+    // A last box of size 0 extends to the end of the file: give it its size
+    // so the new box is not appended inside it
+    if be(&data[last.start..last.start + 4]) == 0 {
+        let size = u32::try_from(data.len() - last.start)
+            .map_err(|_| "HEIF box too large to close".to_string())?;
+        out[last.start..last.start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+    let payload_pos = out.len() + 8;
+    out.extend_from_slice(&((payload.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    out.extend_from_slice(&payload);
+    let offset = (payload_pos as u64)
+        .checked_sub(item.base_offset)
+        .ok_or("Invalid Exif item base offset")?;
+    put(&mut out, item.offset_pos, item.offset_size, offset)?;
+    put(&mut out, item.length_pos, item.length_size, payload.len() as u64)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ascii, bx, tiff};
+    use exif::{In, Reader, Tag};
+    use std::io::Cursor;
+
+    /// A HEIC file with an image item and an Exif item stored in mdat
+    fn heic(tiff: &[u8]) -> Vec<u8> {
+        let ftyp = bx(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let mut infe_image = vec![2, 0, 0, 0, 0, 1, 0, 0];
+        infe_image.extend_from_slice(b"hvc1\0");
+        let mut infe_exif = vec![2, 0, 0, 0, 0, 2, 0, 0];
+        infe_exif.extend_from_slice(b"Exif\0");
+        let iinf = bx(
+            b"iinf",
+            &[vec![0, 0, 0, 0, 0, 2], bx(b"infe", &infe_image), bx(b"infe", &infe_exif)].concat(),
+        );
+        let mut payload = vec![0, 0, 0, 6];
+        payload.extend_from_slice(b"Exif\0\0");
+        payload.extend_from_slice(tiff);
+        let image = [0xAAu8; 16];
+        // iloc v0 with 4 byte offsets and lengths, patched below
+        let iloc_len = 8 + 4 + 2 + 2 + 2 * 14;
+        let meta_len = 8 + 4 + bx(b"hdlr", &[0; 25]).len() + iinf.len() + iloc_len;
+        let mdat_content = ftyp.len() + meta_len + 8;
+        let mut iloc = vec![0, 0, 0, 0, 0x44, 0x00, 0, 2];
+        for (id, offset, len) in [
+            (1u16, mdat_content, image.len()),
+            (2u16, mdat_content + image.len(), payload.len()),
+        ] {
+            iloc.extend_from_slice(&id.to_be_bytes());
+            iloc.extend_from_slice(&[0, 0, 0, 1]);
+            iloc.extend_from_slice(&(offset as u32).to_be_bytes());
+            iloc.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        let meta = bx(
+            b"meta",
+            &[vec![0, 0, 0, 0], bx(b"hdlr", &[0; 25]), iinf, bx(b"iloc", &iloc)].concat(),
+        );
+        assert_eq!(meta.len(), meta_len);
+        [ftyp, meta, bx(b"mdat", &[&image[..], &payload].concat())].concat()
+    }
+
+    fn date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2020-02-03T04:05:06+09:00").unwrap()
+    }
+
+    fn check(updated: &[u8]) {
+        // Through our own box parsing and through kamadak-exif's HEIF reader
+        let ours = Reader::new().read_raw(exif_tiff(updated).unwrap().to_vec()).unwrap();
+        let theirs = Reader::new()
+            .read_from_container(&mut Cursor::new(updated))
+            .unwrap();
+        for exif in [ours, theirs] {
+            let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+            assert_eq!(field.display_value().to_string(), "2020-02-03 04:05:06");
+            let field = exif.get_field(Tag::Model, In::PRIMARY).unwrap();
+            assert_eq!(field.display_value().to_string(), "\"iPhone\"");
+        }
+    }
+
+    #[test]
+    fn reads_exif_item() {
+        let block = tiff(&[ascii(Tag::Model, In::PRIMARY, "iPhone")], false);
+        assert_eq!(exif_tiff(&heic(&block)).unwrap(), &block[..]);
+    }
+
+    #[test]
+    fn updates_in_place() {
+        let old = "2022:01:01 00:00:00";
+        let block = tiff(&[
+            ascii(Tag::Model, In::PRIMARY, "iPhone"),
+            ascii(Tag::DateTime, In::PRIMARY, old),
+            ascii(Tag::DateTimeOriginal, In::PRIMARY, old),
+            ascii(Tag::DateTimeDigitized, In::PRIMARY, old),
+            ascii(Tag::OffsetTime, In::PRIMARY, "+00:00"),
+            ascii(Tag::OffsetTimeOriginal, In::PRIMARY, "+00:00"),
+            ascii(Tag::OffsetTimeDigitized, In::PRIMARY, "+00:00"),
+        ], false);
+        let original = heic(&block);
+        let updated = write_dates(&original, date()).unwrap();
+        assert_eq!(updated.len(), original.len());
+        check(&updated);
+    }
+
+    #[test]
+    fn relocates_grown_block() {
+        let original = heic(&tiff(&[ascii(Tag::Model, In::PRIMARY, "iPhone")], false));
+        let updated = write_dates(&original, date()).unwrap();
+        assert!(updated.len() > original.len());
+        // The image data stays where it was
+        let image = original.windows(16).position(|w| w == [0xAA; 16]).unwrap();
+        assert_eq!(&updated[image..image + 16], &[0xAA; 16]);
+        check(&updated);
+    }
+
+    #[test]
+    fn closes_a_last_box_of_size_zero() {
+        let mut original = heic(&tiff(&[ascii(Tag::Model, In::PRIMARY, "iPhone")], false));
+        let mdat = boxes(&original, 0..original.len()).unwrap().pop().unwrap();
+        original[mdat.start..mdat.start + 4].copy_from_slice(&[0; 4]);
+        let updated = write_dates(&original, date()).unwrap();
+        let top = boxes(&updated, 0..updated.len()).unwrap();
+        let types: Vec<&[u8; 4]> = top.iter().map(|b| &b.typ).collect();
+        assert_eq!(types, [b"ftyp", b"meta", b"mdat", b"mdat"]);
+        check(&updated);
+    }
+
+    #[test]
+    fn rejects_invalid_field_sizes() {
+        let mut data = heic(&tiff(&[ascii(Tag::Model, In::PRIMARY, "iPhone")], false));
+        let iloc = data.windows(4).position(|w| w == b"iloc").unwrap();
+        // 9 byte offsets and 4 byte lengths
+        data[iloc + 8] = 0x94;
+        assert_eq!(exif_tiff(&data), Err("Invalid iloc field size 9".to_string()));
+        let mut out = [0; 16];
+        assert!(put(&mut out, 0, 9, 1).is_err());
+        assert!(put(&mut out, 0, 4, 1 << 32).is_err());
+        assert!(put(&mut out, 0, 0, 0).is_err());
+        put(&mut out, 8, 8, 1 << 32).unwrap();
+        assert_eq!(out[8..], [0, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_truncated_boxes() {
+        let ftyp = bx(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let children = [vec![0, 0, 0, 0], bx(b"iloc", &[0; 8]), bx(b"iinf", &[])];
+        let meta = bx(b"meta", &children.concat());
+        assert!(exif_tiff(&[ftyp, meta].concat()).is_err());
+    }
+}
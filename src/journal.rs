@@ -0,0 +1,173 @@
+//! Journal of the dates written, one JSON object per line, so that changes
+//! can be reviewed and reverted.
+
+use crate::resolver::Resolution;
+use chrono::{DateTime, FixedOffset, Local};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Which date of a file was changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    Metadata,
+    FileTime,
+}
+
+/// A date written to a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub target: Target,
+    /// The date before the change, None when there was none
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: DateTime<FixedOffset>,
+    /// When the change was made
+    pub at: DateTime<FixedOffset>,
+}
+
+impl Entry {
+    /// The entry recording a resolution applied to a file, None for
+    /// resolutions that write nothing
+    pub fn applied(path: &Path, resolution: &Resolution) -> Option<Self> {
+        let (target, from, to) = match resolution {
+            Resolution::WriteMetadata { from, to } => (Target::Metadata, *from, *to),
+            Resolution::SetFileTime { from, to } => (Target::FileTime, *from, *to),
+            _ => return None,
+        };
+        Some(Entry {
+            path: absolute(path),
+            target,
+            from,
+            to,
+            at: Local::now().fixed_offset(),
+        })
+    }
+
+    /// The resolution restoring the date before the change, None when there
+    /// was no date to restore
+    pub fn undo(&self) -> Option<Resolution> {
+        let from = Some(self.to);
+        let to = self.from?;
+        Some(match self.target {
+            Target::Metadata => Resolution::WriteMetadata { from, to },
+            Target::FileTime => Resolution::SetFileTime { from, to },
+        })
+    }
+}
+
+/// The resolutions undoing entries, None for those with no date to restore.
+/// Entries are undone latest first, so every date ends up as it was before
+/// the first change, and file times last, since writing metadata resets them.
+pub fn undo_all(entries: &[Entry]) -> Vec<(&Entry, Option<Resolution>)> {
+    let (metadata, file_times): (Vec<&Entry>, Vec<&Entry>) = entries
+        .iter()
+        .rev()
+        .partition(|e| e.target == Target::Metadata);
+    metadata
+        .into_iter()
+        .chain(file_times)
+        .map(|entry| (entry, entry.undo()))
+        .collect()
+}
+
+/// A journal file that entries are appended to
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Journal {
+    /// Open a journal for appending, creating it if needed
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Could not open journal {}: {}", path.display(), e))?;
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an entry, as a single line so concurrent records do not mix
+    pub fn record(&self, entry: &Entry) -> Result<(), String> {
+        let mut line = serde_json::to_string(entry).map_err(|e| e.to_string())?;
+        line.push('\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())
+            .map_err(|e| format!("Could not write journal {}: {}", self.path.display(), e))
+    }
+
+    /// Every entry of a journal file, oldest first
+    pub fn read(path: &Path) -> Result<Vec<Entry>, String> {
+        read_lines(path, "journal")
+    }
+}
+
+/// The canonical form of a path, so that journals and plans do not depend on
+/// the working directory, or the path itself when it cannot be resolved
+pub fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Read a file of one JSON object per line, skipping blank lines. `what` is
+/// the kind of file, for error messages.
+pub(crate) fn read_lines<T: DeserializeOwned>(path: &Path, what: &str) -> Result<Vec<T>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {} {}: {}", what, path.display(), e))?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| format!("Invalid {} entry at line {}: {}", what, n + 1, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn records_and_reads_entries() {
+        let date = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+        let dir = TempDir::new("journal");
+        let path = dir.join("journal.jsonl");
+        let journal = Journal::open(&path).unwrap();
+        let changes = [
+            Resolution::WriteMetadata {
+                from: None,
+                to: date("2019-08-02T10:00:00+02:00"),
+            },
+            Resolution::SetFileTime {
+                from: Some(date("2024-01-01T10:00:00+00:00")),
+                to: date("2019-08-02T08:00:00+00:00"),
+            },
+            Resolution::Unchanged,
+        ];
+        let entries: Vec<Entry> = changes
+            .iter()
+            .filter_map(|c| Entry::applied(Path::new("a.jpg"), c))
+            .collect();
+        for entry in &entries {
+            journal.record(entry).unwrap();
+        }
+        assert_eq!(Journal::read(&path).unwrap(), entries);
+        assert_eq!(
+            entries[1].undo(),
+            Some(Resolution::SetFileTime {
+                from: Some(date("2019-08-02T08:00:00+00:00")),
+                to: date("2024-01-01T10:00:00+00:00"),
+            })
+        );
+        assert_eq!(entries[0].undo(), None);
+    }
+}
@@ -0,0 +1,254 @@
+//! Locating and replacing the EXIF APP1 segment of JPEG files.
+
+use crate::tiff;
+use chrono::{DateTime, FixedOffset};
+
+const SOI: u8 = 0xD8;
+const SOS: u8 = 0xDA;
+const EOI: u8 = 0xD9;
+const APP0: u8 = 0xE0;
+const APP1: u8 = 0xE1;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// Largest payload a segment can hold, the length field counts itself
+const MAX_SEGMENT_PAYLOAD: usize = 0xFFFF - 2;
+
+/// A marker segment and the position of its payload
+struct Segment {
+    marker: u8,
+    /// Start of the marker (the 0xFF byte)
+    start: usize,
+    payload: std::ops::Range<usize>,
+}
+
+/// List the segments before the image data
+fn segments(data: &[u8]) -> Result<Vec<Segment>, String> {
+    if data.get(0..2) != Some(&[0xFF, SOI]) {
+        return Err("Not a JPEG file".to_string());
+    }
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        let start = pos;
+        // Skip fill bytes before the marker
+        while data.get(pos) == Some(&0xFF) && data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        if data.get(pos) != Some(&0xFF) {
+            return Err("Invalid JPEG marker".to_string());
+        }
+        let marker = *data.get(pos + 1).ok_or("Truncated JPEG")?;
+        if marker == SOS || marker == EOI {
+            segments.push(Segment {
+                marker,
+                start,
+                payload: pos + 2..pos + 2,
+            });
+            return Ok(segments);
+        }
+        if (0xD0..=0xD7).contains(&marker) || marker == 0x01 {
+            pos += 2;
+            continue;
+        }
+        let len = data
+            .get(pos + 2..pos + 4)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+            .ok_or("Truncated JPEG")?;
+        if len < 2 || pos + 2 + len > data.len() {
+            return Err("Invalid JPEG segment length".to_string());
+        }
+        segments.push(Segment {
+            marker,
+            start,
+            payload: pos + 4..pos + 2 + len,
+        });
+        pos += 2 + len;
+    }
+}
+
+fn is_exif(data: &[u8], segment: &Segment) -> bool {
+    segment.marker == APP1 && data[segment.payload.clone()].starts_with(EXIF_HEADER)
+}
+
+/// Return the TIFF block of the EXIF segment, if any
+pub fn exif_tiff(data: &[u8]) -> Result<Option<&[u8]>, String> {
+    Ok(segments(data)?
+        .iter()
+        .find(|s| is_exif(data, s))
+        .map(|s| &data[s.payload.start + EXIF_HEADER.len()..s.payload.end]))
+}
+
+/// Replace the TIFF block of the EXIF segment, inserting a new segment after
+/// SOI and any APP0 (JFIF) segment when there is none
+pub fn replace_exif_tiff(data: &[u8], tiff: &[u8]) -> Result<Vec<u8>, String> {
+    if EXIF_HEADER.len() + tiff.len() > MAX_SEGMENT_PAYLOAD {
+        return Err("EXIF data does not fit in a JPEG segment".to_string());
+    }
+    let segments = segments(data)?;
+    let (cut_start, cut_end) = match segments.iter().find(|s| is_exif(data, s)) {
+        Some(s) => (s.start, s.payload.end),
+        None => {
+            let pos = segments
+                .iter()
+                .take_while(|s| s.marker == APP0)
+                .last()
+                .map(|s| s.payload.end)
+                .unwrap_or(2);
+            (pos, pos)
+        }
+    };
+    let mut out = Vec::with_capacity(data.len() + tiff.len() + 10);
+    out.extend_from_slice(&data[..cut_start]);
+    out.extend_from_slice(&[0xFF, APP1]);
+    out.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+    out.extend_from_slice(EXIF_HEADER);
+    out.extend_from_slice(tiff);
+    out.extend_from_slice(&data[cut_end..]);
+    Ok(out)
+}
+
+/// Set the EXIF dates of a JPEG file held in memory
+pub fn write_dates(data: &[u8], date: DateTime<FixedOffset>) -> Result<Vec<u8>, String> {
+    let tiff = tiff::with_dates(exif_tiff(data)?, date)?;
+    replace_exif_tiff(data, &tiff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ascii, tiff};
+    use crate::timezone::with_offset;
+    use chrono::NaiveDate;
+    use exif::{In, Reader, Tag, Value};
+    use std::io::Cursor;
+
+    fn date() -> DateTime<FixedOffset> {
+        let naive = NaiveDate::from_ymd_opt(2021, 6, 5)
+            .unwrap()
+            .and_hms_opt(4, 3, 2)
+            .unwrap();
+        with_offset(naive, FixedOffset::east_opt(2 * 3600).unwrap())
+    }
+
+    /// A JPEG with a JFIF segment, an optional EXIF segment and no image data
+    fn jpeg(tiff: Option<&[u8]>) -> Vec<u8> {
+        let mut data = vec![0xFF, SOI, 0xFF, APP0, 0x00, 0x07];
+        data.extend_from_slice(b"JFIF\0");
+        data.extend_from_slice(&[0xFF, EOI]);
+        match tiff {
+            Some(tiff) => replace_exif_tiff(&data, tiff).unwrap(),
+            None => data,
+        }
+    }
+
+    fn read(data: &[u8]) -> exif::Exif {
+        Reader::new()
+            .read_from_container(&mut Cursor::new(data))
+            .unwrap()
+    }
+
+    fn read_ascii(exif: &exif::Exif, tag: Tag) -> Option<String> {
+        match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Ascii(v) => Some(String::from_utf8_lossy(&v[0]).to_string()),
+            _ => None,
+        }
+    }
+
+    fn assert_dates(exif: &exif::Exif) {
+        for tag in [Tag::DateTimeOriginal, Tag::DateTimeDigitized, Tag::DateTime] {
+            assert_eq!(
+                read_ascii(exif, tag).as_deref(),
+                Some("2021:06:05 04:03:02"),
+                "{}",
+                tag
+            );
+        }
+        for tag in [Tag::OffsetTimeOriginal, Tag::OffsetTimeDigitized, Tag::OffsetTime] {
+            assert_eq!(read_ascii(exif, tag).as_deref(), Some("+02:00"), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn creates_exif_segment() {
+        let updated = write_dates(&jpeg(None), date()).unwrap();
+        assert_dates(&read(&updated));
+        // The JFIF segment must stay first
+        assert_eq!(&updated[2..4], &[0xFF, APP0]);
+        assert_eq!(&updated[11..13], &[0xFF, APP1]);
+    }
+
+    #[test]
+    fn overwrites_dates_in_place() {
+        for little_endian in [true, false] {
+            let fields = [
+                ascii(Tag::Make, In::PRIMARY, "Canon"),
+                ascii(Tag::DateTime, In::PRIMARY, "2022:01:01 00:00:00"),
+                ascii(Tag::DateTimeOriginal, In::PRIMARY, "2022:01:01 00:00:00"),
+                ascii(Tag::DateTimeDigitized, In::PRIMARY, "2022:01:01 00:00:00"),
+                ascii(Tag::OffsetTime, In::PRIMARY, "-05:00"),
+                ascii(Tag::OffsetTimeOriginal, In::PRIMARY, "-05:00"),
+                ascii(Tag::OffsetTimeDigitized, In::PRIMARY, "-05:00"),
+                ascii(Tag::ImageUniqueID, In::PRIMARY, "0123456789abcdef"),
+            ];
+            let original = tiff(&fields, little_endian);
+            let updated = write_dates(&jpeg(Some(&original)), date()).unwrap();
+            let exif = read(&updated);
+            assert_dates(&exif);
+            assert_eq!(read_ascii(&exif, Tag::Make).as_deref(), Some("Canon"));
+            assert_eq!(
+                read_ascii(&exif, Tag::ImageUniqueID).as_deref(),
+                Some("0123456789abcdef")
+            );
+            // Only the date bytes change
+            let new_tiff = exif_tiff(&updated).unwrap().unwrap();
+            assert_eq!(new_tiff.len(), original.len());
+            let changed = new_tiff
+                .iter()
+                .zip(&original)
+                .filter(|(a, b)| a != b)
+                .count();
+            assert!(changed <= 3 * 19 + 3 * 6);
+        }
+    }
+
+    #[test]
+    fn overwrites_sub_second_tags() {
+        let fields = [
+            ascii(Tag::DateTimeOriginal, In::PRIMARY, "2022:01:01 00:00:00"),
+            ascii(Tag::SubSecTimeOriginal, In::PRIMARY, "25"),
+            ascii(Tag::SubSecTime, In::PRIMARY, "123"),
+        ];
+        let original = tiff(&fields, true);
+        let updated = write_dates(&jpeg(Some(&original)), date()).unwrap();
+        let exif = read(&updated);
+        assert_dates(&exif);
+        assert_eq!(read_ascii(&exif, Tag::SubSecTimeOriginal).as_deref(), Some("0"));
+        assert_eq!(read_ascii(&exif, Tag::SubSecTime).as_deref(), Some("0"));
+        assert_eq!(read_ascii(&exif, Tag::SubSecTimeDigitized), None);
+        let fraction = date() + chrono::Duration::milliseconds(250);
+        let updated = write_dates(&updated, fraction).unwrap();
+        let exif = read(&updated);
+        for tag in [Tag::SubSecTimeOriginal, Tag::SubSecTimeDigitized, Tag::SubSecTime] {
+            assert_eq!(read_ascii(&exif, tag).as_deref(), Some("25"), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn adds_missing_tags_and_keeps_others() {
+        let fields = [
+            ascii(Tag::Make, In::PRIMARY, "Nikon"),
+            ascii(Tag::Model, In::PRIMARY, "D750"),
+            ascii(Tag::Model, In::THUMBNAIL, "thumb"),
+        ];
+        let original = tiff(&fields, false);
+        let updated = write_dates(&jpeg(Some(&original)), date()).unwrap();
+        let exif = read(&updated);
+        assert_dates(&exif);
+        assert_eq!(read_ascii(&exif, Tag::Make).as_deref(), Some("Nikon"));
+        assert_eq!(read_ascii(&exif, Tag::Model).as_deref(), Some("D750"));
+        let thumb = exif.get_field(Tag::Model, In::THUMBNAIL).unwrap();
+        assert_eq!(thumb.display_value().to_string(), "\"thumb\"");
+        // The original block is kept as a prefix
+        let new_tiff = exif_tiff(&updated).unwrap().unwrap();
+        assert_eq!(&new_tiff[8..original.len()], &original[8..]);
+    }
+}
@@ -0,0 +1,35 @@
+//! Recover the capture date of photos and videos from heuristics such as the
+//! filename, embedded metadata and the filesystem, and write it back.
+//!
+//! Dates are collected as [`Candidate`]s by [`DateSource`]s, a [`Resolver`]
+//! decides what should change and a [`Writer`] applies the result. The
+//! [`Pipeline`] bundles them together for a single file.
+
+pub mod drift;
+pub mod duration;
+pub mod heif;
+pub mod jpeg;
+pub mod journal;
+pub mod mp4;
+pub mod patterns;
+pub mod pipeline;
+pub mod plan;
+pub mod png;
+pub mod resolver;
+pub mod sequence;
+pub mod source;
+pub mod takeout;
+#[cfg(test)]
+mod test_util;
+pub mod tiff;
+pub mod timezone;
+pub mod webp;
+pub mod writer;
+pub mod xmp;
+
+pub use patterns::{FilenameMatch, PatternRegistry};
+pub use pipeline::Pipeline;
+pub use resolver::{Decision, Resolution, Resolver};
+pub use source::{Candidate, DateSource, SourceKind};
+pub use timezone::TimeZoneSpec;
+pub use writer::Writer;
@@ -1,213 +1,745 @@
-use chrono::{NaiveDate, NaiveDateTime};
-use clap::Parser;
-use filetime::{FileTime, set_file_times};
-use exif::{In, Reader, Tag, Value};
-use log::{info, warn};
-use regex::Regex;
-use std::fs;
-use std::fs::File;
-use std::process::Command;
-use walkdir::WalkDir;
-use std::path::Path;
-use pretty_env_logger;
+use chrono::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
+use heuristic_dates::drift::{self, Device, DeviceFile};
+use heuristic_dates::duration::{format_duration, parse_duration};
+use heuristic_dates::journal::{self, Entry, Journal};
+use heuristic_dates::plan::{self, Change};
+use heuristic_dates::resolver::{Plausibility, Policy};
+use heuristic_dates::writer::{ExiftoolWriter, NativeWriter, Unsupported, XmpSidecarWriter};
+use heuristic_dates::xmp::{self, SidecarNaming};
+use heuristic_dates::{
+    Decision, FilenameMatch, PatternRegistry, Pipeline, Resolution, Resolver, SourceKind,
+    TimeZoneSpec, Writer,
+};
+use log::{debug, info, warn};
 use rayon::prelude::*;
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Backend used to write metadata dates
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum WriterArg {
+    /// Write metadata in-process
+    Native,
+    /// Run exiftool for every file
+    Exiftool,
+    /// Write an XMP sidecar next to the file, as native does for RAW files
+    XmpSidecar,
+    /// Do not write metadata, only report
+    None,
+}
+
+/// Commands; without one, files are scanned, resolved and changed in a
+/// single pass
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the files with a date in their name or directory
+    Scan,
+
+    /// Decide the changes for a directory and write them to a plan file, one
+    /// JSON object per line, without changing anything
+    Plan {
+        /// Plan file to write
+        plan: PathBuf,
+    },
+
+    /// Apply the changes of a plan file, possibly after reviewing or editing
+    /// it. Changes to files whose date is no longer the one planned from are
+    /// skipped.
+    Apply {
+        /// Plan file to read
+        plan: PathBuf,
+    },
+
+    /// Restore the dates from before the changes recorded in a journal
+    Undo {
+        /// Journal to revert, by default the one given with --journal
+        journal: Option<PathBuf>,
+    },
+
+    /// Show every date candidate of a file and the resolver's choice
+    Inspect {
+        /// File to inspect
+        file: PathBuf,
+    },
+
+    /// Shift the embedded and filesystem dates of files by a fixed amount,
+    /// e.g. after a trip across time zones
+    Shift {
+        /// Signed amount to add, e.g. 3h12m or -1d
+        #[arg(long, allow_hyphen_values = true, value_parser = parse_duration)]
+        by: Duration,
+
+        /// Files, or directories whose media files are all shifted
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+}
 
 /// Command line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Input directory path
-    #[arg(long)]
-    input: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Input directory path, required without a command and by scan and plan
+    #[arg(long, global = true)]
+    input: Option<String>,
 
     /// Output directory path
     #[arg(long)]
     output: Option<String>,
 
     /// Dry run mode: no changes will be made
-    #[arg(long)]
+    #[arg(long, global = true)]
     dry_run: bool,
+
+    /// File every change made is appended to, one JSON object per line
+    #[arg(long, global = true, default_value = "heuristic-dates-journal.jsonl")]
+    journal: PathBuf,
+
+    /// TOML or YAML file with additional filename patterns
+    #[arg(long, global = true)]
+    patterns: Option<String>,
+
+    /// Comma separated list of image extensions, replacing the defaults
+    #[arg(long, global = true, value_delimiter = ',')]
+    image_extensions: Option<Vec<String>>,
+
+    /// Comma separated list of video extensions, replacing the defaults
+    #[arg(long, global = true, value_delimiter = ',')]
+    video_extensions: Option<Vec<String>>,
+
+    /// Backend used to write metadata dates
+    #[arg(long, global = true, value_enum, default_value_t = WriterArg::Native)]
+    writer: WriterArg,
+
+    /// Sidecar naming: "append" for file.NEF.xmp, "replace" for file.xmp
+    #[arg(long, global = true, default_value_t = SidecarNaming::Append)]
+    sidecar_naming: SidecarNaming,
+
+    /// Time zone of dates without an offset: an IANA name such as
+    /// Europe/Berlin, a fixed offset such as +02:00, or "local"
+    #[arg(long, global = true, default_value_t = TimeZoneSpec::Local)]
+    timezone: TimeZoneSpec,
+
+    /// Do not infer the time zone from the GPS position of photos
+    #[arg(long, global = true)]
+    no_gps_timezone: bool,
+
+    /// Do not derive dates from directory names such as "2019-07 Summer Trip"
+    #[arg(long, global = true)]
+    no_directory_dates: bool,
+
+    /// Confidence weight of a kind of source, e.g. exif=0.8 or filesystem=0;
+    /// kinds are filename, exif, container, sidecar, takeout, directory and
+    /// filesystem
+    #[arg(long, global = true, value_delimiter = ',', value_parser = parse_weight)]
+    weight: Vec<(SourceKind, f64)>,
+
+    /// How the date to keep is chosen: weighted, prefer-filename,
+    /// prefer-exif, earliest, latest, exif-if-plausible or fill-missing-only
+    #[arg(long, global = true, default_value_t = Policy::Weighted)]
+    policy: Policy,
+
+    /// Largest difference between dates that still agree, e.g. 90s, 5m or
+    /// 1h; date-only filenames agree with any time on their day
+    #[arg(long, global = true, default_value = "1m", value_parser = parse_duration)]
+    tolerance: Duration,
+
+    /// Changes with a lower confidence, from 0 to 1, are reported but not
+    /// applied
+    #[arg(long, global = true, default_value_t = 0.5)]
+    min_confidence: f64,
+
+    /// Dates before this year are rejected as implausible
+    #[arg(long, global = true, default_value_t = 1995)]
+    min_year: i32,
+
+    /// How far in the future dates may be before they are rejected, e.g. 1d
+    #[arg(long, global = true, default_value = "1d", value_parser = parse_duration)]
+    future_margin: Duration,
+
+    /// Release year of a camera model, as "model=year" with the model as in
+    /// its EXIF data; earlier dates of its files are rejected
+    #[arg(long, global = true, value_parser = parse_camera_year)]
+    camera_year: Vec<(String, i32)>,
+
+    /// Correct camera clocks instead: shift the EXIF dates of every file by
+    /// the clock offset of the device that took it
+    #[arg(long, global = true)]
+    clock_drift: bool,
+
+    /// Clock offset of a device, as "device=duration", e.g.
+    /// "Canon EOS 5D=-1h12m" for every body of the model or
+    /// "Canon EOS 5D #1234=-1h12m" for one; other devices get an estimate
+    #[arg(long, global = true, value_parser = parse_device_offset)]
+    device_offset: Vec<(String, Duration)>,
+
+    /// Apply dates interpolated for undated files named by a camera counter,
+    /// such as DSC_0041.JPG, from the dated files numbered around them
+    #[arg(long, global = true)]
+    interpolate: bool,
+
+    /// Largest time between the dated files around an interpolated one
+    #[arg(long, global = true, default_value = "1h", value_parser = parse_duration)]
+    interpolate_span: Duration,
+}
+
+/// Parse a `kind=weight` pair
+fn parse_weight(s: &str) -> Result<(SourceKind, f64), String> {
+    let (kind, weight) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected kind=weight, got '{}'", s))?;
+    let weight: f64 = weight
+        .parse()
+        .map_err(|e| format!("Invalid weight '{}': {}", weight, e))?;
+    if !(weight >= 0.0 && weight.is_finite()) {
+        return Err(format!("Weight must be a non-negative number, got {}", weight));
+    }
+    Ok((kind.parse()?, weight))
+}
+
+/// Parse a `model=year` pair
+fn parse_camera_year(s: &str) -> Result<(String, i32), String> {
+    let (model, year) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected model=year, got '{}'", s))?;
+    let year = year
+        .parse()
+        .map_err(|e| format!("Invalid year '{}': {}", year, e))?;
+    Ok((model.to_string(), year))
+}
+
+/// Parse a `device=duration` pair
+fn parse_device_offset(s: &str) -> Result<(String, Duration), String> {
+    let (device, offset) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("Expected device=duration, got '{}'", s))?;
+    Ok((device.to_string(), parse_duration(offset)?))
 }
 
 fn main() {
     pretty_env_logger::init();
     let args = Args::parse();
-    println!("Input directory: {}", args.input);
+    if let Some(ref input) = args.input {
+        println!("Input directory: {}", input);
+    }
     if args.dry_run {
         println!("Dry run mode: no changes will be made.");
     }
 
-    // Regex patterns for IMG and VID files
-    let img_pattern = Regex::new(r"^IMG_(\d{8})_(\d{6})\d*.*\.jpg$").unwrap();
-    let vid_pattern = Regex::new(r"^VID_(\d{8})_(\d{6})\d*.*\.mp4$").unwrap();
-    let img_date_only_pattern = Regex::new(r"^IMG-(\d{8})-WA\d+.*\.jpg$").unwrap();
-    let screenshot_pattern = Regex::new(r"^Screenshot_(\d{8})-(\d{6}).*\.jpg$").unwrap();
-
-    let mut matched_files = Vec::new();
-    for entry in WalkDir::new(&args.input).into_iter().filter_map(|e| e.ok()) {
-        if entry.file_type().is_file() {
-            let fname = entry.file_name().to_string_lossy();
-            // Only match files with a date in the name
-            let is_img_with_date = img_pattern.is_match(&fname)
-                || img_date_only_pattern.is_match(&fname)
-                || screenshot_pattern.is_match(&fname);
-            let is_vid_with_date = vid_pattern.is_match(&fname);
-            if is_img_with_date || is_vid_with_date {
-                matched_files.push(entry.path().display().to_string());
+    let mut registry = PatternRegistry::builtin();
+    if let Some(ref path) = args.patterns
+        && let Err(e) = registry.load_file(Path::new(path))
+    {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(ref image) = args.image_extensions {
+        registry.media.image = image.clone();
+    }
+    if let Some(ref video) = args.video_extensions {
+        registry.media.video = video.clone();
+    }
+    let writer: Option<Box<dyn Writer>> = match args.writer {
+        WriterArg::Native => Some(Box::new(NativeWriter::new(args.sidecar_naming))),
+        WriterArg::Exiftool => match ExiftoolWriter::detect() {
+            Ok(w) => Some(Box::new(w)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        WriterArg::XmpSidecar => Some(Box::new(XmpSidecarWriter::new(args.sidecar_naming))),
+        WriterArg::None => None,
+    };
+    let mut resolver = Resolver {
+        timezone: args.timezone,
+        policy: args.policy,
+        tolerance: args.tolerance,
+        min_confidence: args.min_confidence,
+        plausibility: Plausibility {
+            min_year: args.min_year,
+            future_margin: args.future_margin,
+            camera_years: args.camera_year.iter().cloned().collect(),
+        },
+        ..Resolver::default()
+    };
+    for (kind, weight) in &args.weight {
+        resolver.weights.set(*kind, *weight);
+    }
+    let pipeline = Pipeline::new(registry)
+        .with_resolver(resolver)
+        .with_gps_timezone(!args.no_gps_timezone)
+        .with_directory_dates(!args.no_directory_dates)
+        .with_metadata_writer(writer);
+    // Changes are only recorded when they are made
+    let writes = matches!(
+        args.command,
+        None | Some(Command::Apply { .. }) | Some(Command::Shift { .. })
+    );
+    let journal = if args.dry_run || !writes {
+        None
+    } else {
+        match Journal::open(&args.journal) {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
         }
+    };
+    let input = args.input.as_deref().ok_or_else(|| "--input is required".to_string());
+    let result = match args.command {
+        None => input.and_then(|input| run(&pipeline, &args, input, journal.as_ref())),
+        Some(Command::Scan) => input.map(|input| scan(&pipeline, input)),
+        Some(Command::Plan { ref plan }) => {
+            input.and_then(|input| make_plan(&pipeline, &args, input, plan))
+        }
+        Some(Command::Apply { ref plan }) => {
+            apply_plan(&pipeline, plan, args.dry_run, journal.as_ref())
+        }
+        Some(Command::Undo { journal: ref path }) => {
+            undo(&pipeline, path.as_ref().unwrap_or(&args.journal), args.dry_run)
+        }
+        Some(Command::Inspect { ref file }) => inspect(&pipeline, file),
+        Some(Command::Shift { by, ref paths }) => {
+            shift(&pipeline, paths, by, args.dry_run, journal.as_ref());
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Scan, resolve and change the files of the input directory in one pass
+fn run(
+    pipeline: &Pipeline,
+    args: &Args,
+    input: &str,
+    journal: Option<&Journal>,
+) -> Result<(), String> {
+    if args.clock_drift {
+        for (path, decision) in drift_decisions(pipeline, args, input)? {
+            process(pipeline, &path, &decision, args.dry_run, journal);
+        }
+        return Ok(());
+    }
+    let unsupported = Unsupported::default();
+
+    let matched_files = pipeline.scan(Path::new(input));
+    if args.interpolate {
+        println!("Interpolated files:");
+        for (path, decision) in interpolated(pipeline, args, input, &matched_files) {
+            println!("File: {} | {}", path.display(), decision.reason);
+            process(pipeline, &path, &decision, args.dry_run, journal);
+        }
     }
     println!("Matched files:");
     // Use rayon for parallel file processing
-    matched_files.par_iter().for_each(|file| {
-        let fname = std::path::Path::new(file)
-            .file_name()
-            .map(|f| f.to_string_lossy())
-            .unwrap_or_default();
-        let mut date = "unknown".to_string();
-        let mut time = "unknown".to_string();
-        if let Some(caps) = img_pattern.captures(&fname) {
-            date = caps.get(1).map(|m| m.as_str().to_string()).unwrap_or(date.clone());
-            time = caps.get(2).map(|m| m.as_str().to_string()).unwrap_or(time.clone());
-        } else if let Some(caps) = vid_pattern.captures(&fname) {
-            date = caps.get(1).map(|m| m.as_str().to_string()).unwrap_or(date.clone());
-            time = caps.get(2).map(|m| m.as_str().to_string()).unwrap_or(time.clone());
-        } else if let Some(caps) = img_date_only_pattern.captures(&fname) {
-            date = caps.get(1).map(|m| m.as_str().to_string()).unwrap_or(date.clone());
-        } else if let Some(caps) = screenshot_pattern.captures(&fname) {
-            date = caps.get(1).map(|m| m.as_str().to_string()).unwrap_or(date.clone());
-            time = caps.get(2).map(|m| m.as_str().to_string()).unwrap_or(time.clone());
-        }
-        println!("File: {} | Date: {} | Time: {}", fname, date, time);
-
-        // Only process JPG files for EXIF
-        if fname.to_lowercase().ends_with(".jpg") && date != "unknown" {
-            let file_handle = File::open(&file);
-            if let Ok(fh) = file_handle {
-                let mut buf_reader = std::io::BufReader::new(fh);
-                let exifreader = Reader::new();
-                let exif = exifreader.read_from_container(&mut buf_reader);
-                if let Ok(exif) = exif {
-                    let exif_date = exif
-                        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
-                        .and_then(|field| match &field.value {
-                            Value::Ascii(vec) if !vec.is_empty() => {
-                                let s = String::from_utf8_lossy(&vec[0]);
-                                chrono::NaiveDateTime::parse_from_str(&s, "%Y:%m:%d %H:%M:%S").ok()
-                            }
-                            _ => None,
-                        });
-                    let parsed_date = if time != "unknown" {
-                        chrono::NaiveDateTime::parse_from_str(
-                            &format!("{} {}", date, time),
-                            "%Y%m%d %H%M%S",
-                        )
-                        .ok()
-                    } else {
-                        chrono::NaiveDate::parse_from_str(&date, "%Y%m%d")
-                            .ok()
-                            .and_then(|d| d.and_hms_opt(0, 0, 0))
-                    };
-                    match (parsed_date, exif_date) {
-                        (Some(parsed), Some(exif_dt)) => {
-                            if parsed < exif_dt {
-                                if args.dry_run {
-                                    info!(
-                                        "[DRY RUN] Would modify EXIF date for file: {} from {} to {}",
-                                        file, exif_dt, parsed
-                                    );
-                                } else {
-                                    // Set EXIF date to parsed
-                                    match set_exif_date(&file, parsed) {
-                                        Ok(_) => info!(
-                                            "Modified EXIF date for file: {} from {} to {}",
-                                            file, exif_dt, parsed
-                                        ),
-                                        Err(e) => warn!(
-                                            "Failed to modify EXIF date for file: {}: {}",
-                                            file, e
-                                        ),
-                                    }
-                                }
-                            } else {
-                                info!("No change needed for file: {}", file);
-                            }
-                        }
-                        _ => {
-                            warn!("Could not parse date for file: {}", file);
-                        }
-                    }
-                } else {
-                    warn!("No EXIF data found for file: {}", file);
-                    // Try to set file creation time if we have a valid date
-                    let parsed_date = if time != "unknown" {
-                        NaiveDateTime::parse_from_str(
-                            &format!("{} {}", date, time),
-                            "%Y%m%d %H%M%S",
-                        )
-                        .ok()
-                    } else {
-                        NaiveDate::parse_from_str(&date, "%Y%m%d")
-                            .ok()
-                            .and_then(|d| d.and_hms_opt(0, 0, 0))
-                    };
-                    if let Some(parsed) = parsed_date {
-                        if args.dry_run {
-                            info!(
-                                "[DRY RUN] Would set file creation time for file: {} to {}",
-                                file, parsed
-                            );
-                        } else {
-                            match set_file_creation_time(&file, parsed) {
-                                Ok(_) => {
-                                    info!("Set file creation time for file: {} to {}", file, parsed)
-                                }
-                                Err(e) => warn!(
-                                    "Failed to set file creation time for file: {}: {}",
-                                    file, e
-                                ),
-                            }
-                        }
-                    } else {
-                        warn!("Could not parse date for file: {}", file);
+    matched_files.par_iter().for_each(|(path, name_match)| {
+        print_match(pipeline, path, name_match.as_ref());
+        let decision = pipeline.resolve(path);
+        // Move the file before changing it, so the journal records where it
+        // ends up
+        let path = match args.output {
+            Some(ref out_dir) => move_file(path, Path::new(out_dir), args),
+            None => path.clone(),
+        };
+        match decision {
+            Some(decision) => process(pipeline, &path, &decision, args.dry_run, journal),
+            None => unsupported.add(&path),
+        }
+    });
+
+    if let Some(summary) = unsupported.summary() {
+        println!(
+            "Writer {} cannot handle these file types: {}",
+            pipeline.writer_name(),
+            summary
+        );
+    }
+    Ok(())
+}
+
+/// Move a file and its sidecar to the output directory, unless in dry-run
+/// mode. Returns where the file is afterwards.
+fn move_file(path: &Path, out_dir: &Path, args: &Args) -> PathBuf {
+    let file = path.display();
+    let out_path = out_dir.join(path.file_name().unwrap_or_default());
+    if args.dry_run {
+        info!("[DRY RUN] Would move file: {} to {}", file, out_path.display());
+        return path.to_path_buf();
+    }
+    if let Err(e) = fs::rename(path, &out_path) {
+        warn!("Failed to move file: {} to {}: {}", file, out_path.display(), e);
+        return path.to_path_buf();
+    }
+    info!("Moved file: {} to {}", file, out_path.display());
+    // Keep the sidecar next to its file
+    let sidecar = xmp::sidecar_path(path, args.sidecar_naming);
+    if sidecar.exists() {
+        let out_sidecar = xmp::sidecar_path(&out_path, args.sidecar_naming);
+        if let Err(e) = fs::rename(&sidecar, &out_sidecar) {
+            warn!("Failed to move sidecar: {}: {}", sidecar.display(), e);
+        }
+    }
+    out_path
+}
+
+/// Print how the date of a scanned file was found
+fn print_match(pipeline: &Pipeline, path: &Path, name_match: Option<&FilenameMatch>) {
+    let fname = path
+        .file_name()
+        .map(|f| f.to_string_lossy())
+        .unwrap_or_default();
+    match name_match {
+        Some(name_match) => {
+            let time = name_match
+                .time
+                .map(|t| t.format("%H%M%S").to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            println!(
+                "File: {} | Rule: {} | Date: {} | Time: {}",
+                fname,
+                name_match.rule,
+                name_match.date.format("%Y%m%d"),
+                time
+            );
+        }
+        None => {
+            if let Some(dir_match) = path
+                .parent()
+                .and_then(|dir| pipeline.registry().match_directory(dir))
+            {
+                println!(
+                    "File: {} | Directory rule: {} | Dates: {} to {}",
+                    fname,
+                    dir_match.rule,
+                    dir_match.first.format("%Y%m%d"),
+                    dir_match.last.format("%Y%m%d")
+                );
+            }
+        }
+    }
+}
+
+/// List the files with a date in their name or directory
+fn scan(pipeline: &Pipeline, input: &str) {
+    println!("Matched files:");
+    for (path, name_match) in pipeline.scan(Path::new(input)) {
+        print_match(pipeline, &path, name_match.as_ref());
+    }
+}
+
+/// Decisions for the undated files of numbered sequences, leaving out the
+/// scanned files, which are resolved on their own
+fn interpolated(
+    pipeline: &Pipeline,
+    args: &Args,
+    input: &str,
+    matched_files: &[(PathBuf, Option<FilenameMatch>)],
+) -> Vec<(PathBuf, Decision)> {
+    let scanned: HashSet<&PathBuf> = matched_files.iter().map(|(path, _)| path).collect();
+    let files = pipeline.media_files(Path::new(input));
+    pipeline
+        .interpolate(&files, args.interpolate_span)
+        .into_iter()
+        .filter(|(path, _)| !scanned.contains(path))
+        .collect()
+}
+
+/// Decisions shifting the EXIF dates of the files of every device with a
+/// clock offset. Given offsets are not applied again to files the journal
+/// records as shifted by them.
+fn drift_decisions(
+    pipeline: &Pipeline,
+    args: &Args,
+    input: &str,
+) -> Result<Vec<(PathBuf, Decision)>, String> {
+    let entries = if args.journal.exists() {
+        Journal::read(&args.journal)?
+    } else {
+        Vec::new()
+    };
+    let files: Vec<DeviceFile> = pipeline
+        .media_files(Path::new(input))
+        .par_iter()
+        .filter_map(|path| pipeline.device_file(path))
+        .collect();
+    let offsets = drift::offsets(&files, &args.device_offset, args.tolerance);
+    let devices: BTreeSet<&Device> = files.iter().map(|f| &f.device).collect();
+    for device in devices {
+        match offsets.get(device) {
+            Some(offset) => println!("Device: {} | Clock offset: {}", device, offset),
+            None => info!("No clock offset for device: {}", device),
+        }
+    }
+    Ok(files
+        .into_iter()
+        .filter_map(|file| {
+            let offset = offsets.get(&file.device)?;
+            if drift::already_shifted(&entries, &file, offset, args.tolerance) {
+                info!(
+                    "Clock offset of {} already applied to file: {}",
+                    file.device,
+                    file.path.display()
+                );
+                return None;
+            }
+            println!(
+                "File: {} | Device: {} | Before: {} | After: {}",
+                file.path.display(),
+                file.device,
+                file.exif,
+                file.exif + offset.duration
+            );
+            let decision = drift::decision(&file, offset);
+            Some((file.path, decision))
+        })
+        .collect())
+}
+
+/// Write the changes decided for the input directory to a plan file
+fn make_plan(pipeline: &Pipeline, args: &Args, input: &str, plan: &Path) -> Result<(), String> {
+    let decisions = if args.clock_drift {
+        drift_decisions(pipeline, args, input)?
+    } else {
+        let matched_files = pipeline.scan(Path::new(input));
+        let mut decisions: Vec<(PathBuf, Decision)> = matched_files
+            .par_iter()
+            .filter_map(|(path, _)| Some((path.clone(), pipeline.resolve(path)?)))
+            .collect();
+        if args.interpolate {
+            decisions.extend(interpolated(pipeline, args, input, &matched_files));
+        }
+        decisions
+    };
+    let changes: Vec<Change> = decisions
+        .iter()
+        .filter_map(|(path, decision)| {
+            if let Resolution::Uncertain { to } = decision.resolution {
+                info!(
+                    "Not planning date for file: {} to {}, confidence {:.2} is too low: {}",
+                    path.display(),
+                    to,
+                    decision.confidence,
+                    decision.reason
+                );
+            }
+            Change::decided(path, decision)
+        })
+        .collect();
+    plan::write(plan, &changes)?;
+    println!("Planned {} change(s) in {}", changes.len(), plan.display());
+    Ok(())
+}
+
+/// Apply the changes of a plan file in order, skipping those to files whose
+/// date changed since the plan was made
+fn apply_plan(
+    pipeline: &Pipeline,
+    plan: &Path,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) -> Result<(), String> {
+    let mut stale = 0;
+    for change in plan::read(plan)? {
+        if !pipeline.is_current(&change.path, change.target, change.from) {
+            let from = change.from.map_or("none".to_string(), |d| d.to_string());
+            warn!(
+                "Skipping stale change for file: {}, its date is no longer {}",
+                change.path.display(),
+                from
+            );
+            stale += 1;
+            continue;
+        }
+        process(pipeline, &change.path, &change.decision(), dry_run, journal);
+    }
+    if stale > 0 {
+        println!("Skipped {} stale change(s), plan again to update them", stale);
+    }
+    Ok(())
+}
+
+/// Restore the dates recorded in a journal as they were before the first
+/// change. Undoing is not recorded.
+fn undo(pipeline: &Pipeline, journal: &Path, dry_run: bool) -> Result<(), String> {
+    let entries = Journal::read(journal)?;
+    for (entry, resolution) in journal::undo_all(&entries) {
+        match resolution {
+            Some(resolution) => {
+                let decision = Decision {
+                    resolution,
+                    confidence: 1.0,
+                    reason: format!("undo of the change made at {}", entry.at),
+                };
+                process(pipeline, &entry.path, &decision, dry_run, None);
+            }
+            None => warn!(
+                "No previous date to restore for file: {}, keeping {}",
+                entry.path.display(),
+                entry.to
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Print every candidate of a file and the decision for it
+fn inspect(pipeline: &Pipeline, path: &Path) -> Result<(), String> {
+    if !path.is_file() {
+        return Err(format!("No such file: {}", path.display()));
+    }
+    println!("File: {}", path.display());
+    let (candidates, has_metadata) = pipeline.candidates(path);
+    for candidate in &candidates {
+        let mut date = candidate.datetime.to_string();
+        if let Some(offset) = candidate.offset {
+            date.push_str(&format!(" {}", offset));
+        }
+        if let Some(until) = candidate.until {
+            date.push_str(&format!(" to {}", until));
+        }
+        if candidate.malformed {
+            date = "not a date".to_string();
+        }
+        println!("Candidate: {} | {} | {}", candidate.kind, candidate.label, date);
+    }
+    if !has_metadata {
+        println!("Metadata: none");
+    }
+    match pipeline.resolve(path) {
+        Some(decision) => {
+            println!(
+                "Decision: {} (confidence {:.2})",
+                decision.resolution, decision.confidence
+            );
+            println!("Reason: {}", decision.reason);
+        }
+        None => println!(
+            "Decision: none, writer {} cannot handle this file",
+            pipeline.writer_name()
+        ),
+    }
+    Ok(())
+}
+
+/// Log and, unless in dry-run mode, apply the decision for a file and record
+/// it in the journal
+fn process(
+    pipeline: &Pipeline,
+    path: &Path,
+    decision: &Decision,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) {
+    let file = path.display();
+    debug!(
+        "Decision for file: {} (confidence {:.2}): {}",
+        file, decision.confidence, decision.reason
+    );
+    let resolution = &decision.resolution;
+    match resolution {
+        Resolution::Unchanged => info!("No change needed for file: {}", file),
+        Resolution::Unresolved(reason) => {
+            warn!("Could not parse date for file: {}: {}", file, reason)
+        }
+        Resolution::Rejected(reason) => warn!("Rejected date for file: {}: {}", file, reason),
+        Resolution::Uncertain { to } => warn!(
+            "Not changing date for file: {} to {}, confidence {:.2} is too low: {}",
+            file, to, decision.confidence, decision.reason
+        ),
+        Resolution::WriteMetadata { from, to } => {
+            let from = from.map_or("none".to_string(), |d| d.to_string());
+            if !pipeline.writes_metadata() {
+                info!(
+                    "Metadata writing disabled, not modifying metadata date for file: {} from {} to {}",
+                    file, from, to
+                );
+            } else if dry_run {
+                info!(
+                    "[DRY RUN] Would modify metadata date for file: {} from {} to {}",
+                    file, from, to
+                );
+            } else {
+                match pipeline.apply(path, resolution) {
+                    Ok(_) => {
+                        info!("Modified metadata date for file: {} from {} to {}", file, from, to);
+                        record(journal, path, resolution);
                     }
+                    Err(e) => warn!("Failed to modify metadata date for file: {}: {}", file, e),
                 }
-            } else {
-                warn!("Could not open file: {}", file);
             }
         }
-        // Move all processed files to output directory if specified and not in dry-run mode
-        if let Some(ref out_dir) = args.output {
-            let out_path = Path::new(out_dir).join(fname.as_ref());
-            if args.dry_run {
-                info!("[DRY RUN] Would move file: {} to {}", file, out_path.display());
+        Resolution::SetFileTime { to, .. } => {
+            if !pipeline.writes_metadata() {
+                info!(
+                    "Writing disabled, not setting file creation time for file: {} to {}",
+                    file, to
+                );
+            } else if dry_run {
+                info!("[DRY RUN] Would set file creation time for file: {} to {}", file, to);
             } else {
-                match fs::rename(&file, &out_path) {
-                    Ok(_) => info!("Moved file: {} to {}", file, out_path.display()),
-                    Err(e) => warn!("Failed to move file: {} to {}: {}", file, out_path.display(), e),
+                match pipeline.apply(path, resolution) {
+                    Ok(_) => {
+                        info!("Set file creation time for file: {} to {}", file, to);
+                        record(journal, path, resolution);
+                    }
+                    Err(e) => warn!("Failed to set file creation time for file: {}: {}", file, e),
                 }
             }
         }
-    });
+    }
 }
 
-fn set_exif_date(file_path: &str, new_date: NaiveDateTime) -> Result<(), String> {
-    let formatted = new_date.format("%Y:%m:%d %H:%M:%S").to_string();
-    let status = Command::new("exiftool")
-        .arg("-DateTimeOriginal=".to_owned() + &formatted)
-        .arg(file_path)
-        .status();
-    match status {
-        Ok(s) if s.success() => Ok(()),
-        Ok(s) => Err(format!("exiftool failed with status: {}", s)),
-        Err(e) => Err(format!("Failed to run exiftool: {}", e)),
+/// Record an applied change in the journal, if there is one
+fn record(journal: Option<&Journal>, path: &Path, resolution: &Resolution) {
+    if let Some(journal) = journal
+        && let Some(entry) = Entry::applied(path, resolution)
+        && let Err(e) = journal.record(&entry)
+    {
+        warn!("{}", e);
     }
 }
 
-fn set_file_creation_time(file_path: &str, new_date: NaiveDateTime) -> Result<(), String> {
-    let ft = FileTime::from_unix_time(new_date.and_utc().timestamp(), 0);
-    let meta = fs::metadata(file_path).map_err(|e| e.to_string())?;
-    let atime = FileTime::from_last_access_time(&meta);
-    set_file_times(file_path, atime, ft).map_err(|e| e.to_string())?;
-    Ok(())
+/// Shift the dates of the given files, and of the media files in the given
+/// directories
+fn shift(
+    pipeline: &Pipeline,
+    paths: &[PathBuf],
+    by: Duration,
+    dry_run: bool,
+    journal: Option<&Journal>,
+) {
+    let files: Vec<PathBuf> = paths
+        .iter()
+        .flat_map(|path| {
+            if path.is_dir() {
+                pipeline.media_files(path)
+            } else {
+                vec![path.clone()]
+            }
+        })
+        .collect();
+    files.par_iter().for_each(|path| {
+        let changes = pipeline.shift(path, by);
+        if changes.is_empty() {
+            warn!("No date to shift for file: {}", path.display());
+        }
+        for resolution in changes {
+            let decision = Decision {
+                resolution,
+                confidence: 1.0,
+                reason: format!("shift by {}", format_duration(by)),
+            };
+            process(pipeline, path, &decision, dry_run, journal);
+        }
+    });
 }
@@ -0,0 +1,419 @@
+//! Dates in MP4 and QuickTime containers.
+//!
+//! The `mvhd`, `tkhd` and `mdhd` boxes hold creation and modification times
+//! as seconds since 1904 in UTC. QuickTime files may also carry a `©day`
+//! text in `udta` or `ilst`, and a `com.apple.quicktime.creationdate` entry
+//! in the `keys`/`ilst` metadata. All of them are updated in place, so the
+//! file layout and chunk offsets never change.
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Seconds between 1904-01-01 and 1970-01-01
+const EPOCH_1904: i64 = 2_082_844_800;
+const APPLE_CREATION_DATE: &str = "com.apple.quicktime.creationdate";
+
+/// How a date is stored in the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Seconds since 1904, 32 bits
+    Seconds32,
+    /// Seconds since 1904, 64 bits
+    Seconds64,
+    /// ISO 8601 text of a fixed length, with a numeric offset (`+0100`)
+    TextOffset(usize),
+    /// ISO 8601 text of a fixed length, in UTC (`Z`)
+    TextUtc(usize),
+}
+
+/// A date field found in the container
+#[derive(Debug, Clone, PartialEq)]
+pub struct DateField {
+    pub label: &'static str,
+    /// Position of the value in the file
+    pub position: u64,
+    pub encoding: Encoding,
+    /// Whether this is a creation rather than a modification time
+    pub creation: bool,
+    /// None when the field is unset (zero)
+    pub value: Option<DateTime<FixedOffset>>,
+}
+
+struct BoxHeader {
+    typ: [u8; 4],
+    content: u64,
+    end: u64,
+}
+
+fn read_exact_at<R: Read + Seek>(r: &mut R, pos: u64, buf: &mut [u8]) -> Result<(), String> {
+    r.seek(SeekFrom::Start(pos)).map_err(|e| e.to_string())?;
+    r.read_exact(buf).map_err(|e| e.to_string())
+}
+
+fn read_header<R: Read + Seek>(r: &mut R, pos: u64, parent_end: u64) -> Result<BoxHeader, String> {
+    let mut buf = [0u8; 8];
+    read_exact_at(r, pos, &mut buf)?;
+    let size = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64;
+    let typ = [buf[4], buf[5], buf[6], buf[7]];
+    let (content, end) = match size {
+        0 => (pos + 8, parent_end),
+        1 => {
+            let mut large = [0u8; 8];
+            r.read_exact(&mut large).map_err(|e| e.to_string())?;
+            let end = pos.checked_add(u64::from_be_bytes(large)).ok_or("Invalid box size")?;
+            (pos + 16, end)
+        }
+        _ => (pos + 8, pos + size),
+    };
+    if end < content || end > parent_end {
+        return Err("Invalid box size".to_string());
+    }
+    Ok(BoxHeader { typ, content, end })
+}
+
+fn from_1904(seconds: u64) -> Option<DateTime<FixedOffset>> {
+    if seconds == 0 {
+        return None;
+    }
+    let utc = Utc.timestamp_opt(seconds as i64 - EPOCH_1904, 0).single()?;
+    Some(utc.fixed_offset())
+}
+
+fn to_1904(date: DateTime<FixedOffset>) -> u64 {
+    (date.timestamp() + EPOCH_1904).max(0) as u64
+}
+
+/// Parse a QuickTime text date, returning its encoding when it can be
+/// rewritten in the same format
+fn parse_text(text: &str) -> Option<(DateTime<FixedOffset>, Encoding)> {
+    let text = text.trim_end_matches('\0');
+    if let Ok(dt) = DateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%z") {
+        return Some((dt, Encoding::TextOffset(text.len())));
+    }
+    if let Some(utc) = text.strip_suffix('Z')
+        && let Ok(naive) = NaiveDateTime::parse_from_str(utc, "%Y-%m-%dT%H:%M:%S")
+    {
+        return Some((naive.and_utc().fixed_offset(), Encoding::TextUtc(text.len())));
+    }
+    None
+}
+
+fn format_text(date: DateTime<FixedOffset>, encoding: Encoding) -> Option<String> {
+    let (text, len) = match encoding {
+        Encoding::TextOffset(len) => (date.format("%Y-%m-%dT%H:%M:%S%z").to_string(), len),
+        Encoding::TextUtc(len) => (
+            date.with_timezone(&Utc).format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            len,
+        ),
+        _ => return None,
+    };
+    (text.len() == len).then_some(text)
+}
+
+struct Walker {
+    fields: Vec<DateField>,
+    /// Key names of the current `meta` box, indexed from 1 by `ilst` items
+    keys: Vec<String>,
+}
+
+impl Walker {
+    fn walk<R: Read + Seek>(
+        &mut self,
+        r: &mut R,
+        start: u64,
+        end: u64,
+        parent: [u8; 4],
+    ) -> Result<(), String> {
+        let mut pos = start;
+        while pos + 8 <= end {
+            let header = read_header(r, pos, end)?;
+            match &header.typ {
+                b"moov" | b"trak" | b"mdia" | b"udta" => {
+                    self.walk(r, header.content, header.end, header.typ)?
+                }
+                b"meta" => {
+                    // QuickTime meta boxes have no version and flags, the
+                    // ISO ones do
+                    let mut probe = [0u8; 8];
+                    read_exact_at(r, header.content, &mut probe)?;
+                    let content = if &probe[4..8] == b"hdlr" {
+                        header.content
+                    } else {
+                        header.content + 4
+                    };
+                    self.keys.clear();
+                    self.walk(r, content, header.end, header.typ)?;
+                }
+                b"mvhd" | b"tkhd" | b"mdhd" => self.time_box(r, &header)?,
+                b"keys" => self.keys_box(r, &header)?,
+                b"ilst" => self.walk(r, header.content, header.end, header.typ)?,
+                b"\xa9day" if parent == *b"udta" => {
+                    // Length, language, then the text
+                    let mut prefix = [0u8; 4];
+                    read_exact_at(r, header.content, &mut prefix)?;
+                    let len = u16::from_be_bytes([prefix[0], prefix[1]]) as u64;
+                    let text_pos = header.content + 4;
+                    if text_pos + len <= header.end {
+                        self.text_field(r, "QuickTime ©day", text_pos, len)?;
+                    }
+                }
+                typ if parent == *b"ilst" => {
+                    let index = u32::from_be_bytes(*typ) as usize;
+                    let label = if typ == b"\xa9day" {
+                        Some("QuickTime ©day")
+                    } else if index >= 1
+                        && self.keys.get(index - 1).map(String::as_str) == Some(APPLE_CREATION_DATE)
+                    {
+                        Some("QuickTime com.apple.quicktime.creationdate")
+                    } else {
+                        None
+                    };
+                    if let Some(label) = label {
+                        self.data_box(r, &header, label)?;
+                    }
+                }
+                _ => {}
+            }
+            pos = header.end;
+        }
+        Ok(())
+    }
+
+    fn time_box<R: Read + Seek>(&mut self, r: &mut R, header: &BoxHeader) -> Result<(), String> {
+        let label = match &header.typ {
+            b"mvhd" => "QuickTime mvhd",
+            b"tkhd" => "QuickTime tkhd",
+            _ => "QuickTime mdhd",
+        };
+        let mut version = [0u8; 1];
+        read_exact_at(r, header.content, &mut version)?;
+        let (encoding, size) = if version[0] == 1 {
+            (Encoding::Seconds64, 8)
+        } else {
+            (Encoding::Seconds32, 4)
+        };
+        for (i, creation) in [true, false].into_iter().enumerate() {
+            let position = header.content + 4 + i as u64 * size;
+            let mut buf = vec![0u8; size as usize];
+            read_exact_at(r, position, &mut buf)?;
+            let seconds = buf.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64);
+            self.fields.push(DateField {
+                label,
+                position,
+                encoding,
+                creation,
+                value: from_1904(seconds),
+            });
+        }
+        Ok(())
+    }
+
+    fn keys_box<R: Read + Seek>(&mut self, r: &mut R, header: &BoxHeader) -> Result<(), String> {
+        let mut count = [0u8; 4];
+        read_exact_at(r, header.content + 4, &mut count)?;
+        let mut pos = header.content + 8;
+        self.keys.clear();
+        for _ in 0..u32::from_be_bytes(count) {
+            let mut head = [0u8; 8];
+            read_exact_at(r, pos, &mut head)?;
+            let size = u32::from_be_bytes([head[0], head[1], head[2], head[3]]) as u64;
+            if size < 8 || pos + size > header.end {
+                return Err("Invalid keys entry".to_string());
+            }
+            let mut name = vec![0u8; size as usize - 8];
+            r.read_exact(&mut name).map_err(|e| e.to_string())?;
+            self.keys.push(String::from_utf8_lossy(&name).to_string());
+            pos += size;
+        }
+        Ok(())
+    }
+
+    /// A `data` box: type indicator, locale, then the value
+    fn data_box<R: Read + Seek>(
+        &mut self,
+        r: &mut R,
+        header: &BoxHeader,
+        label: &'static str,
+    ) -> Result<(), String> {
+        let data = read_header(r, header.content, header.end)?;
+        if &data.typ == b"data" && data.content + 8 <= data.end {
+            self.text_field(r, label, data.content + 8, data.end - data.content - 8)?;
+        }
+        Ok(())
+    }
+
+    fn text_field<R: Read + Seek>(
+        &mut self,
+        r: &mut R,
+        label: &'static str,
+        position: u64,
+        len: u64,
+    ) -> Result<(), String> {
+        let mut text = vec![0u8; len as usize];
+        read_exact_at(r, position, &mut text)?;
+        if let Some((value, encoding)) = parse_text(&String::from_utf8_lossy(&text)) {
+            self.fields.push(DateField {
+                label,
+                position,
+                encoding,
+                creation: true,
+                value: Some(value),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// List the date fields of an MP4 or QuickTime file
+pub fn read_fields<R: Read + Seek>(r: &mut R) -> Result<Vec<DateField>, String> {
+    let end = r.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    let first = read_header(r, 0, end)?;
+    if !matches!(&first.typ, b"ftyp" | b"moov" | b"mdat" | b"wide" | b"free") {
+        return Err("Not an MP4 or QuickTime file".to_string());
+    }
+    let mut walker = Walker {
+        fields: Vec::new(),
+        keys: Vec::new(),
+    };
+    walker.walk(r, 0, end, *b"root")?;
+    Ok(walker.fields)
+}
+
+/// Set every date field to the given date. Text fields whose new value
+/// would not have the same length are left alone and their labels returned.
+pub fn write_dates<R: Read + Write + Seek>(
+    r: &mut R,
+    date: DateTime<FixedOffset>,
+) -> Result<Vec<&'static str>, String> {
+    let mut skipped = Vec::new();
+    for field in read_fields(r)? {
+        let bytes = match field.encoding {
+            Encoding::Seconds32 => {
+                let seconds = u32::try_from(to_1904(date)).map_err(|_| "Date out of range")?;
+                seconds.to_be_bytes().to_vec()
+            }
+            Encoding::Seconds64 => to_1904(date).to_be_bytes().to_vec(),
+            encoding => match format_text(date, encoding) {
+                Some(text) => text.into_bytes(),
+                None => {
+                    skipped.push(field.label);
+                    continue;
+                }
+            },
+        };
+        r.seek(SeekFrom::Start(field.position))
+            .map_err(|e| e.to_string())?;
+        r.write_all(&bytes).map_err(|e| e.to_string())?;
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::bx;
+    use std::io::Cursor;
+
+    fn time_box(typ: &[u8; 4], version: u8, seconds: u64) -> Vec<u8> {
+        let mut content = vec![version, 0, 0, 0];
+        for _ in 0..2 {
+            if version == 1 {
+                content.extend_from_slice(&seconds.to_be_bytes());
+            } else {
+                content.extend_from_slice(&(seconds as u32).to_be_bytes());
+            }
+        }
+        content.extend_from_slice(&[0; 20]);
+        bx(typ, &content)
+    }
+
+    fn data(text: &str) -> Vec<u8> {
+        let mut content = vec![0, 0, 0, 1, 0, 0, 0, 0];
+        content.extend_from_slice(text.as_bytes());
+        bx(b"data", &content)
+    }
+
+    /// A QuickTime file with every kind of date field
+    fn fixture(seconds: u64) -> Vec<u8> {
+        let mut day = vec![0, 24, 0x15, 0xc7];
+        day.extend_from_slice(b"2023-01-01T12:34:56+0100");
+        let mut keys = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        let key = APPLE_CREATION_DATE.as_bytes();
+        keys.extend_from_slice(&((key.len() + 8) as u32).to_be_bytes());
+        keys.extend_from_slice(b"mdta");
+        keys.extend_from_slice(key);
+        let meta = [
+            bx(b"hdlr", &[0; 24]),
+            bx(b"keys", &keys),
+            bx(b"ilst", &bx(&1u32.to_be_bytes(), &data("2023-01-01T12:34:56+0100"))),
+        ]
+        .concat();
+        let moov = [
+            time_box(b"mvhd", 0, seconds),
+            bx(
+                b"trak",
+                &[
+                    time_box(b"tkhd", 0, seconds),
+                    bx(b"mdia", &time_box(b"mdhd", 1, seconds)),
+                ]
+                .concat(),
+            ),
+            bx(b"udta", &bx(b"\xa9day", &day)),
+            bx(b"meta", &meta),
+        ]
+        .concat();
+        [bx(b"ftyp", b"qt  \0\0\0\0qt  "), bx(b"moov", &moov), bx(b"mdat", &[1, 2, 3])].concat()
+    }
+
+    fn render(fields: &[DateField]) -> Vec<(&'static str, bool, String)> {
+        fields
+            .iter()
+            .map(|f| (f.label, f.creation, f.value.unwrap().to_rfc3339()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_all_fields() {
+        // 2023-01-01 11:34:56 UTC
+        let seconds = 1_672_572_896 + EPOCH_1904 as u64;
+        let fields = read_fields(&mut Cursor::new(fixture(seconds))).unwrap();
+        let utc = "2023-01-01T11:34:56+00:00".to_string();
+        let local = "2023-01-01T12:34:56+01:00".to_string();
+        assert_eq!(
+            render(&fields),
+            vec![
+                ("QuickTime mvhd", true, utc.clone()),
+                ("QuickTime mvhd", false, utc.clone()),
+                ("QuickTime tkhd", true, utc.clone()),
+                ("QuickTime tkhd", false, utc.clone()),
+                ("QuickTime mdhd", true, utc.clone()),
+                ("QuickTime mdhd", false, utc),
+                ("QuickTime ©day", true, local.clone()),
+                ("QuickTime com.apple.quicktime.creationdate", true, local),
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_in_place() {
+        let original = fixture(EPOCH_1904 as u64 + 1_700_000_000);
+        let mut file = Cursor::new(original.clone());
+        let date = DateTime::parse_from_rfc3339("2020-02-03T04:05:06-03:00").unwrap();
+        assert!(write_dates(&mut file, date).unwrap().is_empty());
+        let updated = file.into_inner();
+        assert_eq!(updated.len(), original.len());
+        let fields = read_fields(&mut Cursor::new(updated)).unwrap();
+        assert_eq!(fields.len(), 8);
+        for field in fields {
+            assert_eq!(field.value, Some(date), "{}", field.label);
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_box_sizes() {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(read_fields(&mut Cursor::new(data)).is_err());
+    }
+}
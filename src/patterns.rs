@@ -0,0 +1,698 @@
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use regex::Regex;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// A single filename rule as written in a pattern file
+#[derive(Deserialize, Debug, Clone)]
+pub struct PatternSpec {
+    /// Unique name of the rule, used in logs and to override built-in rules
+    pub name: String,
+    /// Regular expression with named captures: year, month, day and
+    /// optionally hour, minute, second, subsecond
+    pub regex: String,
+    /// Kind of media the rule applies to
+    #[serde(default)]
+    pub media: MediaKind,
+    /// Additional file extensions (without the dot) the rule applies to
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Rules with a higher priority are tried first
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// A directory rule as written in a pattern file
+#[derive(Deserialize, Debug, Clone)]
+pub struct DirectoryPatternSpec {
+    /// Unique name of the rule, used in logs and to override built-in rules
+    pub name: String,
+    /// Regular expression matched against the directory path, with `/` as
+    /// separator, with the named capture year and optionally month and day
+    pub regex: String,
+    /// Among matches ending at the same depth, higher priorities win
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// Top level layout of a pattern file
+#[derive(Deserialize, Debug, Default)]
+pub struct PatternFile {
+    /// Replaces the default list of image extensions
+    pub image_extensions: Option<Vec<String>>,
+    /// Replaces the default list of video extensions
+    pub video_extensions: Option<Vec<String>>,
+    #[serde(default)]
+    pub patterns: Vec<PatternSpec>,
+    #[serde(default)]
+    pub directory_patterns: Vec<DirectoryPatternSpec>,
+}
+
+/// Kind of media a rule applies to
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Video,
+    #[default]
+    Any,
+}
+
+/// Extensions recognised as images and videos, compared case-insensitively
+#[derive(Debug, Clone)]
+pub struct MediaExtensions {
+    pub image: Vec<String>,
+    pub video: Vec<String>,
+}
+
+impl Default for MediaExtensions {
+    fn default() -> Self {
+        let list = |exts: &[&str]| exts.iter().map(|e| e.to_string()).collect();
+        MediaExtensions {
+            image: list(&[
+                "jpg", "jpeg", "heic", "heif", "dng", "cr2", "nef", "arw", "png", "webp", "gif",
+            ]),
+            video: list(&["mp4", "mov", "3gp"]),
+        }
+    }
+}
+
+impl MediaExtensions {
+    /// Return the kind of media for an extension, if it is known
+    pub fn kind_of(&self, ext: &str) -> Option<MediaKind> {
+        if self.image.iter().any(|e| e.eq_ignore_ascii_case(ext)) {
+            Some(MediaKind::Image)
+        } else if self.video.iter().any(|e| e.eq_ignore_ascii_case(ext)) {
+            Some(MediaKind::Video)
+        } else {
+            None
+        }
+    }
+}
+
+/// A compiled filename rule
+#[derive(Debug, Clone)]
+pub struct PatternRule {
+    pub name: String,
+    pub regex: Regex,
+    pub media: MediaKind,
+    pub extensions: Vec<String>,
+    pub priority: i32,
+}
+
+/// Date and time extracted from a filename
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilenameMatch {
+    /// Name of the rule that matched
+    pub rule: String,
+    pub date: NaiveDate,
+    /// None when the rule only carries a date
+    pub time: Option<NaiveTime>,
+}
+
+impl FilenameMatch {
+    /// Combine date and time, using midnight when the time is unknown
+    pub fn datetime(&self) -> NaiveDateTime {
+        self.date.and_time(self.time.unwrap_or(NaiveTime::MIN))
+    }
+}
+
+/// A compiled directory rule
+#[derive(Debug, Clone)]
+pub struct DirectoryRule {
+    pub name: String,
+    pub regex: Regex,
+    pub priority: i32,
+}
+
+/// Range of days derived from a directory name, e.g. a whole month for
+/// `2019-07 Summer Trip`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectoryMatch {
+    /// Name of the rule that matched
+    pub rule: String,
+    pub first: NaiveDate,
+    pub last: NaiveDate,
+}
+
+/// Ordered collection of filename and directory rules
+#[derive(Debug, Clone, Default)]
+pub struct PatternRegistry {
+    rules: Vec<PatternRule>,
+    directory_rules: Vec<DirectoryRule>,
+    pub media: MediaExtensions,
+}
+
+/// Built-in rules as (name, regex, media, priority)
+///
+/// The regexes are matched against the file name without its extension.
+const BUILTIN_PATTERNS: &[(&str, &str, MediaKind, i32)] = &[
+    (
+        "img",
+        r"^IMG_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})_(?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})(?P<subsecond>\d*)",
+        MediaKind::Image,
+        0,
+    ),
+    (
+        "vid",
+        r"^VID_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})_(?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})(?P<subsecond>\d*)",
+        MediaKind::Video,
+        0,
+    ),
+    (
+        "whatsapp-image",
+        r"^IMG-(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})-WA\d+",
+        MediaKind::Image,
+        0,
+    ),
+    (
+        "whatsapp-video",
+        r"^VID-(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})-WA\d+",
+        MediaKind::Video,
+        0,
+    ),
+    (
+        "screenshot",
+        r"^Screenshot_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})-(?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})",
+        MediaKind::Image,
+        0,
+    ),
+    (
+        "pixel",
+        r"^PXL_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})_(?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})(?P<subsecond>\d{3})",
+        MediaKind::Any,
+        0,
+    ),
+    (
+        "signal",
+        r"^signal-(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})-(?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})",
+        MediaKind::Any,
+        0,
+    ),
+    (
+        "telegram",
+        r"^(?:photo|video)_(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})_(?P<hour>\d{2})-(?P<minute>\d{2})-(?P<second>\d{2})",
+        MediaKind::Any,
+        0,
+    ),
+    (
+        "screen-recording",
+        r"^Screen Recording (?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2}) at (?P<hour>\d{1,2})\.(?P<minute>\d{2})\.(?P<second>\d{2})",
+        MediaKind::Video,
+        0,
+    ),
+    // Photos and videos exported or uploaded from iOS, e.g. "2023-01-01 12.34.56"
+    (
+        "ios-export",
+        r"^(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2}) (?P<hour>\d{2})\.(?P<minute>\d{2})\.(?P<second>\d{2})",
+        MediaKind::Any,
+        0,
+    ),
+    // Bare camera timestamps are generic, so they are tried last
+    (
+        "samsung",
+        r"^(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})_(?P<hour>\d{2})(?P<minute>\d{2})(?P<second>\d{2})",
+        MediaKind::Any,
+        -10,
+    ),
+];
+
+/// Built-in directory rules as (name, regex, priority)
+///
+/// The regexes are matched against the directory path with `/` separators.
+const BUILTIN_DIRECTORY_PATTERNS: &[(&str, &str, i32)] = &[
+    (
+        "year-month-day-dirs",
+        r"(?:^|/)(?P<year>(?:19|20)\d{2})/(?P<month>0[1-9]|1[0-2])/(?P<day>0[1-9]|[12]\d|3[01])(?:[ _/]|$)",
+        30,
+    ),
+    (
+        "year-month-day",
+        r"(?:^|/)(?P<year>(?:19|20)\d{2})-(?P<month>0[1-9]|1[0-2])-(?P<day>0[1-9]|[12]\d|3[01])(?:[ _/]|$)",
+        30,
+    ),
+    (
+        "year-month-dirs",
+        r"(?:^|/)(?P<year>(?:19|20)\d{2})/(?P<month>0[1-9]|1[0-2])(?:[ _/]|$)",
+        20,
+    ),
+    (
+        "year-month",
+        r"(?:^|/)(?P<year>(?:19|20)\d{2})-(?P<month>0[1-9]|1[0-2])(?:[ _/]|$)",
+        20,
+    ),
+    ("year", r"(?:^|/)(?P<year>(?:19|20)\d{2})(?:[ _/]|$)", 10),
+];
+
+impl PatternSpec {
+    /// Compile the rule, checking that the mandatory captures are present
+    pub fn compile(&self) -> Result<PatternRule, String> {
+        let regex = Regex::new(&self.regex)
+            .map_err(|e| format!("Invalid regex for pattern {}: {}", self.name, e))?;
+        let names: Vec<&str> = regex.capture_names().flatten().collect();
+        for required in ["year", "month", "day"] {
+            if !names.contains(&required) {
+                return Err(format!(
+                    "Pattern {} is missing the named capture '{}'",
+                    self.name, required
+                ));
+            }
+        }
+        Ok(PatternRule {
+            name: self.name.clone(),
+            regex,
+            media: self.media,
+            extensions: self.extensions.clone(),
+            priority: self.priority,
+        })
+    }
+}
+
+impl PatternRule {
+    /// Check whether the rule applies to a file extension
+    pub fn applies_to(&self, ext: &str, media: &MediaExtensions) -> bool {
+        if self.extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)) {
+            return true;
+        }
+        match (self.media, media.kind_of(ext)) {
+            (_, None) => false,
+            (MediaKind::Any, Some(_)) => true,
+            (wanted, Some(kind)) => wanted == kind,
+        }
+    }
+
+    /// Extract the date and time from a file stem matched by this rule
+    pub fn extract(&self, stem: &str) -> Option<FilenameMatch> {
+        let caps = self.regex.captures(stem)?;
+        let number = |name: &str| -> Option<u32> { caps.name(name)?.as_str().parse().ok() };
+        let date = NaiveDate::from_ymd_opt(
+            caps.name("year")?.as_str().parse().ok()?,
+            number("month")?,
+            number("day")?,
+        )?;
+        let time = match number("hour") {
+            Some(hour) => {
+                let nanos = caps
+                    .name("subsecond")
+                    .map(|m| subsecond_nanos(m.as_str()))
+                    .unwrap_or(0);
+                Some(NaiveTime::from_hms_nano_opt(
+                    hour,
+                    number("minute").unwrap_or(0),
+                    number("second").unwrap_or(0),
+                    nanos,
+                )?)
+            }
+            None => None,
+        };
+        Some(FilenameMatch {
+            rule: self.name.clone(),
+            date,
+            time,
+        })
+    }
+}
+
+impl DirectoryPatternSpec {
+    /// Compile the rule, checking that the year capture is present
+    pub fn compile(&self) -> Result<DirectoryRule, String> {
+        let regex = Regex::new(&self.regex)
+            .map_err(|e| format!("Invalid regex for directory pattern {}: {}", self.name, e))?;
+        if !regex.capture_names().flatten().any(|n| n == "year") {
+            return Err(format!(
+                "Directory pattern {} is missing the named capture 'year'",
+                self.name
+            ));
+        }
+        Ok(DirectoryRule {
+            name: self.name.clone(),
+            regex,
+            priority: self.priority,
+        })
+    }
+}
+
+impl DirectoryRule {
+    /// Extract the range of days from the deepest match in a directory path,
+    /// returning it with the position where the match ends. Matching starts
+    /// again at every separator, so a match consuming one does not hide the
+    /// next directory.
+    pub fn extract(&self, dirs: &str) -> Option<(usize, DirectoryMatch)> {
+        let starts = std::iter::once(0).chain(dirs.match_indices('/').map(|(i, _)| i));
+        let caps = starts
+            .filter_map(|start| self.regex.captures_at(dirs, start))
+            .max_by_key(|caps| caps.get(0).map_or(0, |m| m.end()))?;
+        let end = caps.get(0)?.end();
+        let number = |name: &str| -> Option<u32> { caps.name(name)?.as_str().parse().ok() };
+        let year = caps.name("year")?.as_str().parse().ok()?;
+        let (first, last) = match (number("month"), number("day")) {
+            (Some(month), Some(day)) => {
+                let date = NaiveDate::from_ymd_opt(year, month, day)?;
+                (date, date)
+            }
+            (Some(month), None) => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let next = first.checked_add_months(chrono::Months::new(1))?;
+                (first, next.pred_opt()?)
+            }
+            _ => (
+                NaiveDate::from_ymd_opt(year, 1, 1)?,
+                NaiveDate::from_ymd_opt(year, 12, 31)?,
+            ),
+        };
+        Some((
+            end,
+            DirectoryMatch {
+                rule: self.name.clone(),
+                first,
+                last,
+            },
+        ))
+    }
+}
+
+/// Convert a string of fractional second digits into nanoseconds
+fn subsecond_nanos(digits: &str) -> u32 {
+    let digits: String = digits.chars().take(9).collect();
+    if digits.is_empty() {
+        return 0;
+    }
+    let value: u32 = digits.parse().unwrap_or(0);
+    value * 10u32.pow(9 - digits.len() as u32)
+}
+
+impl PatternRegistry {
+    /// Registry containing only the built-in rules
+    pub fn builtin() -> Self {
+        let rules = BUILTIN_PATTERNS
+            .iter()
+            .map(|(name, regex, media, priority)| {
+                PatternSpec {
+                    name: name.to_string(),
+                    regex: regex.to_string(),
+                    media: *media,
+                    extensions: Vec::new(),
+                    priority: *priority,
+                }
+                .compile()
+                .expect("built-in pattern must compile")
+            })
+            .collect();
+        let directory_rules = BUILTIN_DIRECTORY_PATTERNS
+            .iter()
+            .map(|(name, regex, priority)| {
+                DirectoryPatternSpec {
+                    name: name.to_string(),
+                    regex: regex.to_string(),
+                    priority: *priority,
+                }
+                .compile()
+                .expect("built-in directory pattern must compile")
+            })
+            .collect();
+        let mut registry = PatternRegistry {
+            rules,
+            directory_rules,
+            media: MediaExtensions::default(),
+        };
+        registry.sort();
+        registry
+    }
+
+    /// Load rules and extension lists from a TOML or YAML file, selected by
+    /// its extension
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read pattern file {}: {}", path.display(), e))?;
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let file: PatternFile = match ext.as_str() {
+            "yaml" | "yml" => serde_yaml::from_str(&content).map_err(|e| e.to_string())?,
+            _ => toml::from_str(&content).map_err(|e| e.to_string())?,
+        };
+        let rules = file
+            .patterns
+            .iter()
+            .map(|spec| spec.compile())
+            .collect::<Result<Vec<_>, _>>()?;
+        let directory_rules = file
+            .directory_patterns
+            .iter()
+            .map(|spec| spec.compile())
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(image) = file.image_extensions {
+            self.media.image = image;
+        }
+        if let Some(video) = file.video_extensions {
+            self.media.video = video;
+        }
+        self.extend(rules);
+        self.extend_directories(directory_rules);
+        Ok(())
+    }
+
+    /// Add rules, replacing any existing rule with the same name
+    pub fn extend(&mut self, rules: Vec<PatternRule>) {
+        for rule in rules {
+            self.rules.retain(|r| r.name != rule.name);
+            self.rules.push(rule);
+        }
+        self.sort();
+    }
+
+    /// Add directory rules, replacing any existing rule with the same name
+    pub fn extend_directories(&mut self, rules: Vec<DirectoryRule>) {
+        for rule in rules {
+            self.directory_rules.retain(|r| r.name != rule.name);
+            self.directory_rules.push(rule);
+        }
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        // Stable sort keeps declaration order among rules of equal priority
+        self.rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+        self.directory_rules.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    }
+
+    /// Return the first match among the rules, in priority order
+    pub fn match_name(&self, file_name: &str) -> Option<FilenameMatch> {
+        let path = Path::new(file_name);
+        let ext = path.extension()?.to_string_lossy();
+        let stem = path.file_stem()?.to_string_lossy();
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies_to(&ext, &self.media))
+            .find_map(|rule| rule.extract(&stem))
+    }
+
+    /// Return the range of days named by a directory. Components are tried
+    /// from the deepest up: the first one a match ends in wins, then the rule
+    /// priority.
+    pub fn match_directory(&self, dir: &Path) -> Option<DirectoryMatch> {
+        let components: Vec<String> = dir
+            .components()
+            .filter_map(|c| match c {
+                std::path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+        (1..=components.len()).rev().find_map(|depth| {
+            let dirs = components[..depth].join("/");
+            let last = dirs.len() - components[depth - 1].len();
+            self.directory_rules
+                .iter()
+                .filter_map(|r| r.extract(&dirs))
+                .find(|(end, _)| *end > last)
+                .map(|(_, m)| m)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// File name, expected rule and expected "%Y-%m-%d %H:%M:%S%.f" (or date only)
+    const FIXTURES: &[(&str, &str, &str)] = &[
+        ("IMG_20230101_120000.jpg", "img", "2023-01-01 12:00:00"),
+        ("IMG_20230101_120000123.jpg", "img", "2023-01-01 12:00:00.123"),
+        ("VID_20230101_120000.mp4", "vid", "2023-01-01 12:00:00"),
+        ("IMG-20230101-WA0001.jpg", "whatsapp-image", "2023-01-01"),
+        ("VID-20230101-WA0001.mp4", "whatsapp-video", "2023-01-01"),
+        ("Screenshot_20230101-123456.jpg", "screenshot", "2023-01-01 12:34:56"),
+        ("Screenshot_20230101-123456.png", "screenshot", "2023-01-01 12:34:56"),
+        ("PXL_20230101_123456789.jpg", "pixel", "2023-01-01 12:34:56.789"),
+        ("PXL_20230101_123456789.mp4", "pixel", "2023-01-01 12:34:56.789"),
+        ("20230101_123456.jpg", "samsung", "2023-01-01 12:34:56"),
+        ("signal-2023-01-01-123456.jpg", "signal", "2023-01-01 12:34:56"),
+        ("photo_2023-01-01_12-34-56.jpg", "telegram", "2023-01-01 12:34:56"),
+        (
+            "Screen Recording 2023-01-01 at 12.34.56.mov",
+            "screen-recording",
+            "2023-01-01 12:34:56",
+        ),
+        ("2023-01-01 12.34.56.HEIC", "ios-export", "2023-01-01 12:34:56"),
+        ("2023-01-01 12.34.56-1.mov", "ios-export", "2023-01-01 12:34:56"),
+        ("IMG_20230101_120000.JPG", "img", "2023-01-01 12:00:00"),
+        ("IMG_20230101_120000.jpeg", "img", "2023-01-01 12:00:00"),
+        ("IMG_20230101_120000.HEIC", "img", "2023-01-01 12:00:00"),
+        ("IMG_20230101_120000.dng", "img", "2023-01-01 12:00:00"),
+        ("IMG_20230101_120000.NEF", "img", "2023-01-01 12:00:00"),
+        ("VID_20230101_120000.MOV", "vid", "2023-01-01 12:00:00"),
+        ("VID_20230101_120000.3gp", "vid", "2023-01-01 12:00:00"),
+    ];
+
+    /// File names that no built-in rule should pick up
+    const NON_MATCHING: &[&str] = &[
+        "DSC_0042.jpg",
+        "IMG_20231301_120000.jpg",
+        "IMG-20230101-WA0001.mp4",
+        "VID_20230101_120000.jpg",
+        "IMG_20230101_120000.txt",
+        "Screen Recording 2023-01-01 at 12.34.56.jpg",
+        "notes.txt",
+    ];
+
+    fn render(m: &FilenameMatch) -> String {
+        match m.time {
+            Some(_) => m.datetime().format("%Y-%m-%d %H:%M:%S%.f").to_string(),
+            None => m.date.format("%Y-%m-%d").to_string(),
+        }
+    }
+
+    #[test]
+    fn builtin_fixtures_match() {
+        let registry = PatternRegistry::builtin();
+        for (name, rule, expected) in FIXTURES {
+            let m = registry
+                .match_name(name)
+                .unwrap_or_else(|| panic!("{} did not match", name));
+            assert_eq!(m.rule, *rule, "rule for {}", name);
+            assert_eq!(render(&m), *expected, "date for {}", name);
+        }
+    }
+
+    #[test]
+    fn builtin_fixtures_reject() {
+        let registry = PatternRegistry::builtin();
+        for name in NON_MATCHING {
+            assert_eq!(registry.match_name(name), None, "{} should not match", name);
+        }
+    }
+
+    fn rule(name: &str, regex: &str, media: MediaKind, priority: i32) -> PatternRule {
+        PatternSpec {
+            name: name.to_string(),
+            regex: regex.to_string(),
+            media,
+            extensions: Vec::new(),
+            priority,
+        }
+        .compile()
+        .unwrap()
+    }
+
+    const DAY: &str = r"(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})";
+
+    #[test]
+    fn registry_tries_rules_by_priority() {
+        let mut registry = PatternRegistry::default();
+        registry.extend(vec![
+            rule("low", &format!("^{}", DAY), MediaKind::Any, -1),
+            rule("first", &format!("^{}", DAY), MediaKind::Any, 0),
+            rule("second", &format!("^{}", DAY), MediaKind::Any, 0),
+            rule("high", &format!("^x{}", DAY), MediaKind::Any, 5),
+        ]);
+        assert_eq!(registry.match_name("20230101.jpg").unwrap().rule, "first");
+        assert_eq!(registry.match_name("x20230101.jpg").unwrap().rule, "high");
+    }
+
+    #[test]
+    fn registry_replaces_rules_by_name() {
+        let mut registry = PatternRegistry::default();
+        registry.extend(vec![rule("day", &format!("^a{}", DAY), MediaKind::Any, 0)]);
+        registry.extend(vec![rule("day", &format!("^b{}", DAY), MediaKind::Any, 0)]);
+        assert_eq!(registry.match_name("a20230101.jpg"), None);
+        assert_eq!(registry.match_name("b20230101.jpg").unwrap().rule, "day");
+    }
+
+    #[test]
+    fn registry_filters_by_extension() {
+        let mut registry = PatternRegistry::default();
+        registry.extend(vec![
+            rule("image", &format!("^i{}", DAY), MediaKind::Image, 0),
+            rule("video", &format!("^v{}", DAY), MediaKind::Video, 0),
+        ]);
+        assert!(registry.match_name("i20230101.JPG").is_some());
+        assert!(registry.match_name("i20230101.mp4").is_none());
+        assert!(registry.match_name("v20230101.mp4").is_some());
+        assert!(registry.match_name("v20230101.png").is_none());
+        assert!(registry.match_name("i20230101.txt").is_none());
+        assert!(registry.match_name("i20230101").is_none());
+    }
+
+    #[test]
+    fn file_rules_override_builtin() {
+        let mut registry = PatternRegistry::builtin();
+        let file: PatternFile = toml::from_str(
+            r#"
+            [[patterns]]
+            name = "img"
+            regex = '^IMG_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})'
+            media = "image"
+            "#,
+        )
+        .unwrap();
+        registry.extend(file.patterns.iter().map(|s| s.compile().unwrap()).collect());
+        let m = registry.match_name("IMG_20230101_120000.jpg").unwrap();
+        assert_eq!(m.time, None);
+    }
+
+    #[test]
+    fn matches_directories() {
+        let registry = PatternRegistry::builtin();
+        let cases = [
+            ("photos/2019-07 Summer Trip", "year-month", "2019-07-01", "2019-07-31"),
+            ("photos/2019/07/14", "year-month-day-dirs", "2019-07-14", "2019-07-14"),
+            ("2020/02", "year-month-dirs", "2020-02-01", "2020-02-29"),
+            ("/mnt/2018 Holidays/2019-12-31_party", "year-month-day", "2019-12-31", "2019-12-31"),
+            ("archive/2017", "year", "2017-01-01", "2017-12-31"),
+            ("photos/2019/2020", "year", "2020-01-01", "2020-12-31"),
+            ("2019/2020/Holidays", "year", "2020-01-01", "2020-12-31"),
+        ];
+        for (dir, rule, first, last) in cases {
+            let m = registry
+                .match_directory(Path::new(dir))
+                .unwrap_or_else(|| panic!("{} did not match", dir));
+            assert_eq!(m.rule, rule, "rule for {}", dir);
+            assert_eq!(m.first.to_string(), first, "first day for {}", dir);
+            assert_eq!(m.last.to_string(), last, "last day for {}", dir);
+        }
+        for dir in ["photos/DCIM/100CANON", "backup-2019", "2019-13", "photos/123456"] {
+            assert_eq!(registry.match_directory(Path::new(dir)), None, "{}", dir);
+        }
+    }
+
+    #[test]
+    fn explicit_extensions_extend_media() {
+        let mut registry = PatternRegistry::builtin();
+        let file: PatternFile = toml::from_str(
+            r#"
+            [[patterns]]
+            name = "scan"
+            regex = '^scan_(?P<year>\d{4})(?P<month>\d{2})(?P<day>\d{2})'
+            media = "video"
+            extensions = ["TIF"]
+            "#,
+        )
+        .unwrap();
+        registry.extend(file.patterns.iter().map(|s| s.compile().unwrap()).collect());
+        assert!(registry.match_name("scan_20230101.tif").is_some());
+        assert!(registry.match_name("scan_20230101.mov").is_some());
+        assert!(registry.match_name("scan_20230101.jpg").is_none());
+    }
+}
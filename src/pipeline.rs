@@ -0,0 +1,533 @@
+use crate::drift::{Device, DeviceFile};
+use crate::journal::Target;
+use crate::patterns::{FilenameMatch, PatternRegistry};
+use crate::resolver::{Decision, Resolution, Resolver};
+use crate::sequence::{self, Known};
+use crate::source::{
+    self, Candidate, ContainerSource, DateSource, DirectorySource, ExifSource, FilenameSource,
+    FilesystemSource, SourceKind, TakeoutSource, XmpSource,
+};
+use crate::takeout;
+use crate::timezone::{self, TimeZoneSpec};
+use crate::writer::{FileTimeWriter, NativeWriter, Writer, has_extension};
+use chrono::{DateTime, Duration, FixedOffset};
+use chrono_tz::Tz;
+use exif::Exif;
+use log::debug;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Formats without date metadata, which only get their filesystem time set
+const FILESYSTEM_ONLY: &[&str] = &["gif"];
+
+/// Sources, resolver and writers used to process files
+pub struct Pipeline {
+    registry: PatternRegistry,
+    sources: Vec<Box<dyn DateSource>>,
+    resolver: Resolver,
+    /// None when metadata writing is disabled
+    metadata_writer: Option<Box<dyn Writer>>,
+    /// Infer the time zone from the GPS position of the file
+    gps_timezone: bool,
+    file_time_writer: Box<dyn Writer>,
+}
+
+impl Pipeline {
+    /// Pipeline with the default sources and writers
+    pub fn new(registry: PatternRegistry) -> Self {
+        Pipeline {
+            sources: vec![
+                Box::new(FilenameSource::new(registry.clone())),
+                Box::new(XmpSource),
+                Box::new(ExifSource),
+                Box::new(ContainerSource),
+                Box::new(TakeoutSource),
+                Box::new(DirectorySource::new(registry.clone())),
+                Box::new(FilesystemSource),
+            ],
+            registry,
+            resolver: Resolver::default(),
+            metadata_writer: Some(Box::new(NativeWriter::default())),
+            file_time_writer: Box::new(FileTimeWriter),
+            gps_timezone: true,
+        }
+    }
+
+    /// Add a source, queried after the existing ones
+    pub fn with_source(mut self, source: Box<dyn DateSource>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    pub fn with_resolver(mut self, resolver: Resolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Enable or disable dates derived from directory names
+    pub fn with_directory_dates(mut self, enabled: bool) -> Self {
+        if !enabled {
+            self.sources.retain(|s| s.kind() != SourceKind::Directory);
+        }
+        self
+    }
+
+    fn directory_dates(&self) -> bool {
+        self.sources.iter().any(|s| s.kind() == SourceKind::Directory)
+    }
+
+    pub fn with_gps_timezone(mut self, enabled: bool) -> Self {
+        self.gps_timezone = enabled;
+        self
+    }
+
+    /// Replace the metadata writer, or disable metadata writing with None
+    pub fn with_metadata_writer(mut self, writer: Option<Box<dyn Writer>>) -> Self {
+        self.metadata_writer = writer;
+        self
+    }
+
+    /// Name of the metadata writer, "none" when disabled
+    pub fn writer_name(&self) -> &str {
+        self.metadata_writer.as_ref().map_or("none", |w| w.name())
+    }
+
+    pub fn writes_metadata(&self) -> bool {
+        self.metadata_writer.is_some()
+    }
+
+    pub fn registry(&self) -> &PatternRegistry {
+        &self.registry
+    }
+
+    /// Walk a directory and return the files with a date in their name, and
+    /// the media files in a dated directory when directory dates are enabled
+    pub fn scan(&self, input: &Path) -> Vec<(PathBuf, Option<FilenameMatch>)> {
+        let mut matched_files = Vec::new();
+        for entry in WalkDir::new(input).into_iter().filter_map(|e| e.ok()) {
+            if entry.file_type().is_file() {
+                let fname = entry.file_name().to_string_lossy();
+                if let Some(m) = self.registry.match_name(&fname) {
+                    matched_files.push((entry.path().to_path_buf(), Some(m)));
+                } else if self.directory_dates() && self.in_dated_directory(entry.path()) {
+                    matched_files.push((entry.path().to_path_buf(), None));
+                }
+            }
+        }
+        matched_files
+    }
+
+    /// Walk a directory and return every media file, dated or not
+    pub fn media_files(&self, input: &Path) -> Vec<PathBuf> {
+        WalkDir::new(input)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && self.is_media(e.path()))
+            .map(|e| e.into_path())
+            .collect()
+    }
+
+    fn is_media(&self, path: &Path) -> bool {
+        path.extension()
+            .is_some_and(|ext| self.registry.media.kind_of(&ext.to_string_lossy()).is_some())
+    }
+
+    fn in_dated_directory(&self, path: &Path) -> bool {
+        self.is_media(path)
+            && path
+                .parent()
+                .is_some_and(|dir| self.registry.match_directory(dir).is_some())
+    }
+
+    /// Collect the candidates from every source. The flag tells whether the
+    /// metadata could be read and the metadata writer can write it back.
+    pub fn candidates(&self, path: &Path) -> (Vec<Candidate>, bool) {
+        let writable = !has_extension(path, FILESYSTEM_ONLY)
+            && self.metadata_writer.as_ref().is_none_or(|w| w.supports(path));
+        let mut candidates = Vec::new();
+        let mut has_metadata = false;
+        for source in &self.sources {
+            match source.candidates(path) {
+                Ok(found) => {
+                    has_metadata |= writable && source.kind().is_metadata();
+                    candidates.extend(found);
+                }
+                Err(e) => debug!("{:?} source skipped {}: {}", source.kind(), path.display(), e),
+            }
+        }
+        (candidates, has_metadata)
+    }
+
+    /// Decide what to do with a file, or None if the metadata writer cannot
+    /// handle it
+    pub fn resolve(&self, path: &Path) -> Option<Decision> {
+        if !self.handles(path) {
+            return None;
+        }
+        let (candidates, has_metadata) = self.candidates(path);
+        let exif = source::read_exif(path).ok();
+        let resolver = self.resolver_for(path, exif.as_ref());
+        Some(resolver.resolve(&candidates, has_metadata))
+    }
+
+    /// Whether the writers can handle a file
+    fn handles(&self, path: &Path) -> bool {
+        self.metadata_writer.as_ref().is_none_or(|w| w.supports(path))
+            || has_extension(path, FILESYSTEM_ONLY)
+    }
+
+    /// Dates interpolated for the undated files among `files` that are
+    /// named by a camera counter, from the closest dated files of the same
+    /// directory and prefix. Dated neighbours further apart than `max_span`
+    /// give no date.
+    pub fn interpolate(&self, files: &[PathBuf], max_span: Duration) -> Vec<(PathBuf, Decision)> {
+        // Files by directory and prefix
+        let mut sequences = BTreeMap::<_, Vec<(u64, &PathBuf)>>::new();
+        for path in files {
+            if let Some((prefix, number)) = sequence::sequence_number(path) {
+                let key = (path.parent(), prefix);
+                sequences.entry(key).or_default().push((number, path));
+            }
+        }
+        let mut decisions = Vec::new();
+        for members in sequences.values() {
+            let mut known = Vec::new();
+            let mut undated = Vec::new();
+            for &(number, path) in members {
+                let (candidates, has_metadata) = self.candidates(path);
+                match self.known_date(path, &candidates) {
+                    Some(date) => known.push(Known {
+                        number,
+                        date,
+                        file: path,
+                    }),
+                    None => undated.push((number, path, candidates, has_metadata)),
+                }
+            }
+            for (number, path, candidates, has_metadata) in undated {
+                if !self.handles(path) {
+                    continue;
+                }
+                let Some((to, before, after)) = sequence::interpolate(&known, number, max_span)
+                else {
+                    continue;
+                };
+                // The date replaced, as the resolver shows it: the stored
+                // metadata date even when implausible, or the file time
+                let exif = source::read_exif(path).ok();
+                let timezone = self.resolver_for(path, exif.as_ref()).timezone;
+                let from = candidates
+                    .iter()
+                    .find(|c| {
+                        !c.malformed
+                            && if has_metadata {
+                                c.kind.is_stored()
+                            } else {
+                                c.kind == SourceKind::Filesystem
+                            }
+                    })
+                    .map(|c| c.instant(&timezone));
+                let resolution = if has_metadata {
+                    Resolution::WriteMetadata { from, to }
+                } else {
+                    // Already interpolated
+                    if from.is_some_and(|from| (from - to).abs() <= self.resolver.tolerance) {
+                        continue;
+                    }
+                    Resolution::SetFileTime { from, to }
+                };
+                let name =
+                    |path: &PathBuf| path.file_name().unwrap_or_default().display().to_string();
+                let reason = format!(
+                    "interpolated between {} ({}) and {} ({})",
+                    name(before.file),
+                    before.date,
+                    name(after.file),
+                    after.date
+                );
+                decisions.push((
+                    path.clone(),
+                    Decision {
+                        resolution,
+                        confidence: sequence::INTERPOLATED_CONFIDENCE,
+                        reason,
+                    },
+                ));
+            }
+        }
+        decisions
+    }
+
+    /// The trusted date of a file: its filename date and time, or its
+    /// metadata date, when plausible
+    fn known_date(&self, path: &Path, candidates: &[Candidate]) -> Option<DateTime<FixedOffset>> {
+        let exif = source::read_exif(path).ok();
+        let resolver = self.resolver_for(path, exif.as_ref());
+        candidates
+            .iter()
+            .filter(|c| {
+                (c.kind == SourceKind::Filename && c.until.is_none()) || c.kind.is_metadata()
+            })
+            .map(|c| (c, c.instant(&resolver.timezone)))
+            .find(|(c, instant)| {
+                let camera = resolver.camera.as_deref();
+                resolver.plausibility.check(c, *instant, camera).is_none()
+            })
+            .map(|(_, instant)| instant)
+    }
+
+    /// The resolver set up for a file: its camera model, and the time zone
+    /// at its GPS position
+    fn resolver_for(&self, path: &Path, exif: Option<&Exif>) -> Resolver {
+        let mut resolver = self.resolver.clone();
+        resolver.camera = exif.and_then(source::exif_model);
+        if let Some(tz) = self.gps_zone(path, exif) {
+            debug!("Using time zone {} from GPS position of {}", tz, path.display());
+            resolver.timezone = TimeZoneSpec::Named(tz);
+        }
+        resolver
+    }
+
+    /// The device that took a file, with its EXIF date and how far its
+    /// filename date is ahead, or None without a device or plausible EXIF
+    /// date. Implausible dates are left out as the resolver does.
+    pub fn device_file(&self, path: &Path) -> Option<DeviceFile> {
+        let exif = source::read_exif(path).ok()?;
+        let device = Device::from_exif(&exif)?;
+        let resolver = self.resolver_for(path, Some(&exif));
+        let plausible = |c: &Candidate| {
+            let instant = c.instant(&resolver.timezone);
+            let camera = resolver.camera.as_deref();
+            resolver.plausibility.check(c, instant, camera).is_none().then_some(instant)
+        };
+        let date = source::exif_candidates(&exif).iter().find_map(plausible)?;
+        let difference = FilenameSource::new(self.registry.clone())
+            .candidates(path)
+            .unwrap_or_default()
+            .iter()
+            .filter(|c| c.until.is_none())
+            .find_map(plausible)
+            .map(|instant| instant - date);
+        Some(DeviceFile {
+            path: path.to_path_buf(),
+            device,
+            exif: date,
+            difference,
+        })
+    }
+
+    /// Time zone at the GPS position recorded in the file, or in its Takeout
+    /// JSON, if enabled
+    fn gps_zone(&self, path: &Path, exif: Option<&Exif>) -> Option<Tz> {
+        if !self.gps_timezone {
+            return None;
+        }
+        let (latitude, longitude) = exif
+            .and_then(source::exif_coordinates)
+            .or_else(|| takeout::read(path).ok()?.coordinates())?;
+        timezone::zone_at(latitude, longitude)
+    }
+
+    /// The changes moving the dates of a file by a duration: its embedded
+    /// date, when it has one the metadata writer can handle, then its file
+    /// time. The embedded date is the EXIF or container one, the sidecar one
+    /// only for files without.
+    pub fn shift(&self, path: &Path, by: Duration) -> Vec<Resolution> {
+        let (candidates, _) = self.candidates(path);
+        let exif = source::read_exif(path).ok();
+        let timezone = self.resolver_for(path, exif.as_ref()).timezone;
+        let writable = self.metadata_writer.as_ref().is_none_or(|w| w.supports(path));
+        let embedded = [SourceKind::Exif, SourceKind::Container, SourceKind::Sidecar]
+            .iter()
+            .find_map(|kind| candidates.iter().find(|c| c.kind == *kind && !c.malformed));
+        let file_time = candidates.iter().find(|c| c.kind == SourceKind::Filesystem);
+        let mut changes = Vec::new();
+        if let Some(date) = embedded.filter(|_| writable) {
+            let from = date.instant(&timezone);
+            changes.push(Resolution::WriteMetadata {
+                from: Some(from),
+                to: from + by,
+            });
+        }
+        if let Some(date) = file_time {
+            let from = date.instant(&timezone);
+            changes.push(Resolution::SetFileTime {
+                from: Some(from),
+                to: from + by,
+            });
+        }
+        changes
+    }
+
+    /// Whether a date of a file is still `from`, within the tolerance: the
+    /// embedded date for metadata, the file time otherwise, None meaning
+    /// that there is no such date
+    pub fn is_current(
+        &self,
+        path: &Path,
+        target: Target,
+        from: Option<DateTime<FixedOffset>>,
+    ) -> bool {
+        let (candidates, _) = self.candidates(path);
+        let exif = source::read_exif(path).ok();
+        let timezone = self.resolver_for(path, exif.as_ref()).timezone;
+        let mut current = candidates
+            .iter()
+            .filter(|c| !c.malformed)
+            .filter(|c| match target {
+                Target::Metadata => c.kind.is_stored(),
+                Target::FileTime => c.kind == SourceKind::Filesystem,
+            })
+            .map(|c| c.instant(&timezone));
+        match from {
+            Some(from) => current.any(|date| (date - from).abs() <= self.resolver.tolerance),
+            None => current.next().is_none(),
+        }
+    }
+
+    /// Apply a resolution to a file
+    pub fn apply(&self, path: &Path, resolution: &Resolution) -> Result<(), String> {
+        match resolution {
+            Resolution::WriteMetadata { to, .. } => match self.metadata_writer {
+                Some(ref writer) => writer.write(path, *to),
+                None => Err("Metadata writing is disabled".to_string()),
+            },
+            Resolution::SetFileTime { to, .. } => self.file_time_writer.write(path, *to),
+            Resolution::Unchanged
+            | Resolution::Uncertain { .. }
+            | Resolution::Unresolved(_)
+            | Resolution::Rejected(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{self, Entry};
+    use crate::test_util::{self, TempDir, ascii};
+    use crate::xmp::{self, SidecarNaming};
+    use exif::{In, Tag};
+    use filetime::FileTime;
+    use std::fs;
+
+    /// A JPEG named `name` in `dir`, taken with a Canon EOS 5D, whose EXIF
+    /// DateTimeOriginal is `date`
+    fn exif_jpeg(dir: &TempDir, name: &str, date: &str) -> PathBuf {
+        let fields = [
+            ascii(Tag::Model, In::PRIMARY, "Canon EOS 5D"),
+            ascii(Tag::DateTimeOriginal, In::PRIMARY, date),
+        ];
+        let path = dir.join(name);
+        fs::write(&path, test_util::exif_jpeg(&fields)).unwrap();
+        path
+    }
+
+    fn utc_pipeline() -> Pipeline {
+        Pipeline::new(PatternRegistry::builtin()).with_resolver(Resolver {
+            timezone: "+00:00".parse().unwrap(),
+            ..Resolver::default()
+        })
+    }
+
+    #[test]
+    fn shifts_the_exif_date_before_the_sidecar_one() {
+        let dir = TempDir::new("shift");
+        let path = exif_jpeg(&dir, "DSC_0001.JPG", "2019:08:02 10:00:00");
+        let sidecar = xmp::sidecar_path(&path, SidecarNaming::Append);
+        let date = DateTime::parse_from_rfc3339("2020-01-01T00:00:00+00:00").unwrap();
+        fs::write(&sidecar, xmp::render(date)).unwrap();
+        let changes = utc_pipeline().shift(&path, Duration::hours(1));
+        let Resolution::WriteMetadata { from, to } = changes[0] else {
+            panic!("no metadata change: {:?}", changes);
+        };
+        assert_eq!(from.unwrap().to_rfc3339(), "2019-08-02T10:00:00+00:00");
+        assert_eq!(to.to_rfc3339(), "2019-08-02T11:00:00+00:00");
+    }
+
+    #[test]
+    fn leaves_implausible_dates_out_of_device_files() {
+        let dir = TempDir::new("device");
+        let path = exif_jpeg(&dir, "DSC_0001.JPG", "2019:08:02 10:00:00");
+        let pipeline = utc_pipeline();
+        let file = pipeline.device_file(&path).unwrap();
+        assert_eq!(file.device.to_string(), "Canon EOS 5D");
+        assert_eq!(file.exif.to_rfc3339(), "2019-08-02T10:00:00+00:00");
+        let reset = exif_jpeg(&dir, "DSC_0002.JPG", "1970:01:01 00:00:00");
+        assert!(pipeline.device_file(&reset).is_none());
+    }
+
+    #[test]
+    fn only_sets_the_file_time_of_gifs() {
+        let dir = TempDir::new("gif");
+        let path = dir.join("IMG_20230101_120000.gif");
+        fs::write(&path, b"GIF89a").unwrap();
+        let json = r#"{"photoTakenTime": {"timestamp": "1672574400"}}"#;
+        fs::write(dir.join("IMG_20230101_120000.gif.json"), json).unwrap();
+        let (candidates, has_metadata) = utc_pipeline().candidates(&path);
+        let decision = utc_pipeline().resolve(&path).unwrap();
+        assert!(candidates.iter().any(|c| c.kind == SourceKind::Takeout));
+        assert!(!has_metadata);
+        let Resolution::SetFileTime { to, .. } = decision.resolution else {
+            panic!("no file time change: {:?}", decision);
+        };
+        assert_eq!(to.to_rfc3339(), "2023-01-01T12:00:00+00:00");
+    }
+
+    #[test]
+    fn checks_that_a_date_is_current() {
+        let dir = TempDir::new("current");
+        let path = exif_jpeg(&dir, "DSC_0001.JPG", "2019:08:02 10:00:00");
+        let pipeline = utc_pipeline();
+        let date = |s: &str| Some(DateTime::parse_from_rfc3339(s).unwrap());
+        assert!(pipeline.is_current(&path, Target::Metadata, date("2019-08-02T10:00:30Z")));
+        assert!(!pipeline.is_current(&path, Target::Metadata, date("2019-08-03T10:00:00Z")));
+        assert!(!pipeline.is_current(&path, Target::Metadata, None));
+    }
+
+    #[test]
+    fn interpolation_replaces_the_implausible_metadata_date() {
+        let dir = TempDir::new("interpolate");
+        let files = [
+            exif_jpeg(&dir, "DSC_0001.JPG", "2019:08:02 10:00:00"),
+            exif_jpeg(&dir, "DSC_0002.JPG", "1970:01:01 00:00:00"),
+            exif_jpeg(&dir, "DSC_0003.JPG", "2019:08:03 10:00:00"),
+        ];
+        let pipeline = utc_pipeline();
+        let decisions = pipeline.interpolate(&files, Duration::days(2));
+        assert_eq!(decisions.len(), 1);
+        let (path, decision) = &decisions[0];
+        let Resolution::WriteMetadata { from, to } = decision.resolution else {
+            panic!("no metadata change: {:?}", decisions);
+        };
+        assert_eq!(path, &files[1]);
+        assert_eq!(from.unwrap().to_rfc3339(), "1970-01-01T00:00:00+00:00");
+        assert_eq!(to.to_rfc3339(), "2019-08-02T22:00:00+00:00");
+        assert!(pipeline.is_current(path, Target::Metadata, from));
+    }
+
+    #[test]
+    fn undoing_a_shift_restores_the_file_time() {
+        let dir = TempDir::new("undo");
+        let path = exif_jpeg(&dir, "DSC_0001.JPG", "2019:08:02 10:00:00");
+        let mtime = FileTime::from_unix_time(1_600_000_000, 0);
+        filetime::set_file_mtime(&path, mtime).unwrap();
+
+        let pipeline = utc_pipeline();
+        let mut entries = Vec::new();
+        for change in pipeline.shift(&path, Duration::hours(1)) {
+            pipeline.apply(&path, &change).unwrap();
+            entries.extend(Entry::applied(&path, &change));
+        }
+        assert_eq!(entries.len(), 2);
+        for (entry, resolution) in journal::undo_all(&entries) {
+            pipeline.apply(&entry.path, &resolution.unwrap()).unwrap();
+        }
+        let restored = FileTime::from_last_modification_time(&fs::metadata(&path).unwrap());
+        let (candidates, _) = pipeline.candidates(&path);
+        assert_eq!(restored, mtime);
+        let exif = candidates.iter().find(|c| c.kind == SourceKind::Exif).unwrap();
+        assert_eq!(exif.datetime.to_string(), "2019-08-02 10:00:00");
+    }
+}
@@ -0,0 +1,108 @@
+//! Change plans: the changes decided for a set of files, one JSON object per
+//! line, to be reviewed or edited before they are applied.
+
+use crate::journal::{self, Target};
+use crate::resolver::{Decision, Resolution};
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A date to write to a file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub path: PathBuf,
+    pub target: Target,
+    /// The date when the plan was made, None when there was none
+    pub from: Option<DateTime<FixedOffset>>,
+    pub to: DateTime<FixedOffset>,
+    pub confidence: f64,
+    pub reason: String,
+}
+
+impl Change {
+    /// The change a decision makes to a file, None when it writes nothing
+    pub fn decided(path: &Path, decision: &Decision) -> Option<Self> {
+        let (target, from, to) = match decision.resolution {
+            Resolution::WriteMetadata { from, to } => (Target::Metadata, from, to),
+            Resolution::SetFileTime { from, to } => (Target::FileTime, from, to),
+            _ => return None,
+        };
+        Some(Change {
+            path: journal::absolute(path),
+            target,
+            from,
+            to,
+            confidence: decision.confidence,
+            reason: decision.reason.clone(),
+        })
+    }
+
+    /// The decision carrying out this change
+    pub fn decision(&self) -> Decision {
+        let (from, to) = (self.from, self.to);
+        let resolution = match self.target {
+            Target::Metadata => Resolution::WriteMetadata { from, to },
+            Target::FileTime => Resolution::SetFileTime { from, to },
+        };
+        Decision {
+            resolution,
+            confidence: self.confidence,
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+/// Write a plan, replacing the file
+pub fn write(path: &Path, changes: &[Change]) -> Result<(), String> {
+    let mut text = String::new();
+    for change in changes {
+        text.push_str(&serde_json::to_string(change).map_err(|e| e.to_string())?);
+        text.push('\n');
+    }
+    fs::write(path, text).map_err(|e| format!("Could not write plan {}: {}", path.display(), e))
+}
+
+/// Every change of a plan, in order
+pub fn read(path: &Path) -> Result<Vec<Change>, String> {
+    journal::read_lines(path, "plan")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn round_trips_decisions() {
+        let date = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+        let decisions = [
+            Decision {
+                resolution: Resolution::WriteMetadata {
+                    from: Some(date("2019-08-03T10:00:00+02:00")),
+                    to: date("2019-08-02T10:00:00+02:00"),
+                },
+                confidence: 0.5,
+                reason: "Filename img (2019-08-02 10:00:00 +02:00)".to_string(),
+            },
+            Decision {
+                resolution: Resolution::Uncertain {
+                    to: date("2019-08-02T10:00:00+02:00"),
+                },
+                confidence: 0.3,
+                reason: String::new(),
+            },
+        ];
+        let changes: Vec<Change> = decisions
+            .iter()
+            .filter_map(|d| Change::decided(Path::new("a.jpg"), d))
+            .collect();
+        assert_eq!(changes.len(), 1);
+        let dir = TempDir::new("plan");
+        let path = dir.join("plan.jsonl");
+        write(&path, &changes).unwrap();
+        let read = read(&path).unwrap();
+        assert_eq!(read, changes);
+        assert_eq!(read[0].decision(), decisions[0]);
+    }
+}
@@ -0,0 +1,216 @@
+//! Dates in PNG files: the `eXIf` chunk and the "Creation Time" text chunks.
+
+use crate::tiff;
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
+use std::ops::Range;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const CREATION_TIME: &[u8] = b"Creation Time";
+
+struct Chunk {
+    typ: [u8; 4],
+    /// The whole chunk, from the length field to the CRC
+    range: Range<usize>,
+    data: Range<usize>,
+}
+
+fn chunks(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    if !data.starts_with(SIGNATURE) {
+        return Err("Not a PNG file".to_string());
+    }
+    let mut out = Vec::new();
+    let mut pos = SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let typ: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+        let end = pos + 12 + len;
+        if end > data.len() {
+            return Err("Truncated PNG chunk".to_string());
+        }
+        out.push(Chunk {
+            typ,
+            range: pos..end,
+            data: pos + 8..pos + 8 + len,
+        });
+        pos = end;
+        if &typ == b"IEND" {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+/// CRC-32 as used by PNG chunks
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn make_chunk(typ: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = (data.len() as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(typ);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&out[4..]).to_be_bytes());
+    out
+}
+
+/// Where the text starts in the data of a `tEXt` or uncompressed `iTXt`
+/// chunk with the given keyword
+fn text_start(data: &[u8], chunk: &Chunk, keyword: &[u8]) -> Option<usize> {
+    let body = &data[chunk.data.clone()];
+    body.strip_prefix(keyword)?.strip_prefix(b"\0")?;
+    let start = keyword.len() + 1;
+    match &chunk.typ {
+        b"tEXt" => Some(start),
+        b"iTXt" => {
+            // Compression flag and method, language tag, translated keyword
+            if *body.get(start)? != 0 {
+                return None;
+            }
+            let mut pos = start + 2;
+            for _ in 0..2 {
+                pos += body.get(pos..)?.iter().position(|b| *b == 0)? + 1;
+            }
+            Some(pos)
+        }
+        _ => None,
+    }
+}
+
+/// Text of a `tEXt` or uncompressed `iTXt` chunk with the given keyword
+fn text(data: &[u8], chunk: &Chunk, keyword: &[u8]) -> Option<String> {
+    let text = &data[chunk.data.clone()][text_start(data, chunk, keyword)?..];
+    match &chunk.typ {
+        // Latin-1
+        b"tEXt" => Some(text.iter().map(|b| *b as char).collect()),
+        _ => Some(String::from_utf8_lossy(text).to_string()),
+    }
+}
+
+/// Parse a "Creation Time" value. PNG recommends RFC 1123 but ISO 8601 and
+/// EXIF style dates are common.
+pub fn parse_creation_time(text: &str) -> Option<(NaiveDateTime, Option<FixedOffset>)> {
+    let text = text.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(text).or_else(|_| DateTime::parse_from_rfc2822(text)) {
+        return Some((dt.naive_local(), Some(*dt.offset())));
+    }
+    ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y:%m:%d %H:%M:%S"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(text, fmt).ok())
+        .map(|dt| (dt, None))
+}
+
+/// The "Creation Time" values of the text chunks
+pub fn creation_times(data: &[u8]) -> Result<Vec<(NaiveDateTime, Option<FixedOffset>)>, String> {
+    Ok(chunks(data)?
+        .iter()
+        .filter_map(|c| text(data, c, CREATION_TIME))
+        .filter_map(|t| parse_creation_time(&t))
+        .collect())
+}
+
+/// Set the EXIF dates, creating the `eXIf` chunk before the image data when
+/// missing, and rewrite existing "Creation Time" text chunks
+pub fn write_dates(data: &[u8], date: DateTime<FixedOffset>) -> Result<Vec<u8>, String> {
+    let chunks = chunks(data)?;
+    let exif = chunks.iter().find(|c| &c.typ == b"eXIf");
+    let tiff = tiff::with_dates(exif.map(|c| &data[c.data.clone()]), date)?;
+
+    let mut out = data[..SIGNATURE.len()].to_vec();
+    let mut exif_written = false;
+    for chunk in &chunks {
+        // The eXIf chunk is written again just before the image data
+        if &chunk.typ == b"eXIf" {
+            continue;
+        }
+        if !exif_written && matches!(&chunk.typ, b"IDAT" | b"IEND") {
+            out.extend_from_slice(&make_chunk(b"eXIf", &tiff));
+            exif_written = true;
+        }
+        if let Some(start) = text_start(data, chunk, CREATION_TIME) {
+            // Same chunk type, keyword and language, new text
+            let mut creation = data[chunk.data.start..chunk.data.start + start].to_vec();
+            creation.extend_from_slice(date.to_rfc3339().as_bytes());
+            out.extend_from_slice(&make_chunk(&chunk.typ, &creation));
+        } else {
+            out.extend_from_slice(&data[chunk.range.clone()]);
+        }
+    }
+    if !exif_written {
+        return Err("PNG file has no image data".to_string());
+    }
+    let end = chunks.last().map_or(SIGNATURE.len(), |c| c.range.end);
+    out.extend_from_slice(&data[end..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{In, Reader, Tag};
+    use std::io::Cursor;
+
+    fn png(extra: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = SIGNATURE.to_vec();
+        data.extend_from_slice(&make_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]));
+        for chunk in extra {
+            data.extend_from_slice(chunk);
+        }
+        data.extend_from_slice(&make_chunk(b"IDAT", &[0x78, 0x9c, 0x63, 0, 0, 0, 1, 0, 1]));
+        data.extend_from_slice(&make_chunk(b"IEND", &[]));
+        data
+    }
+
+    #[test]
+    fn crc_matches_reference() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn reads_creation_time() {
+        let itxt = make_chunk(b"iTXt", b"Creation Time\0\0\0en\0\x002023-01-01T12:34:56+02:00");
+        let text = make_chunk(b"tEXt", b"Creation Time\0Sun, 01 Jan 2023 10:00:00 +0000");
+        let data = png(&[itxt, text]);
+        let times = creation_times(&data).unwrap();
+        let render: Vec<String> = times.iter().map(|(dt, off)| format!("{} {:?}", dt, off)).collect();
+        assert_eq!(
+            render,
+            ["2023-01-01 12:34:56 Some(+02:00)", "2023-01-01 10:00:00 Some(+00:00)"]
+        );
+    }
+
+    #[test]
+    fn writes_exif_and_text() {
+        let text = make_chunk(b"tEXt", b"Creation Time\x002022:05:05 10:00:00");
+        let itxt = make_chunk(b"iTXt", b"Creation Time\0\0\0de\0Erstellungszeit\x002022");
+        let other = make_chunk(b"tEXt", b"Software\0test");
+        let date = DateTime::parse_from_rfc3339("2020-02-03T04:05:06+01:00").unwrap();
+        let updated = write_dates(&png(&[text, itxt, other.clone()]), date).unwrap();
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(&updated))
+            .unwrap();
+        let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+        assert_eq!(field.display_value().to_string(), "2020-02-03 04:05:06");
+        let written = (date.naive_local(), Some(*date.offset()));
+        assert_eq!(creation_times(&updated).unwrap(), [written, written]);
+        let itxt = b"Creation Time\0\0\0de\0Erstellungszeit\x002020-02-03T04:05:06+01:00";
+        assert!(updated.windows(itxt.len()).any(|w| w == itxt));
+        let types: Vec<[u8; 4]> = chunks(&updated).unwrap().iter().map(|c| c.typ).collect();
+        assert!(types.contains(b"iTXt"));
+        assert!(updated.windows(other.len()).any(|w| w == other));
+        // Updating again replaces the eXIf chunk instead of adding one
+        let again = write_dates(&updated, date).unwrap();
+        let count = chunks(&again).unwrap().iter().filter(|c| &c.typ == b"eXIf").count();
+        assert_eq!(count, 1);
+    }
+}
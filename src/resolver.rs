@@ -0,0 +1,871 @@
+use crate::source::{Candidate, SourceKind};
+use crate::timezone::TimeZoneSpec;
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Utc};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What should happen to a file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+    /// The metadata already carries an acceptable date
+    Unchanged,
+    /// Rewrite the date in the embedded metadata, or add it when missing
+    WriteMetadata {
+        from: Option<DateTime<FixedOffset>>,
+        to: DateTime<FixedOffset>,
+    },
+    /// The file has no metadata date, set the filesystem time instead
+    SetFileTime {
+        from: Option<DateTime<FixedOffset>>,
+        to: DateTime<FixedOffset>,
+    },
+    /// A better date was found, but with too little confidence to apply it
+    Uncertain { to: DateTime<FixedOffset> },
+    /// Not enough information to decide
+    Unresolved(String),
+    /// The only dates to go on are implausible
+    Rejected(String),
+}
+
+impl fmt::Display for Resolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let none = || "none".to_string();
+        match self {
+            Resolution::Unchanged => write!(f, "unchanged"),
+            Resolution::WriteMetadata { from, to } => {
+                let from = from.map_or_else(none, |d| d.to_string());
+                write!(f, "write metadata from {} to {}", from, to)
+            }
+            Resolution::SetFileTime { from, to } => {
+                let from = from.map_or_else(none, |d| d.to_string());
+                write!(f, "set file time from {} to {}", from, to)
+            }
+            Resolution::Uncertain { to } => write!(f, "uncertain, would set {}", to),
+            Resolution::Unresolved(reason) => write!(f, "unresolved: {}", reason),
+            Resolution::Rejected(reason) => write!(f, "rejected: {}", reason),
+        }
+    }
+}
+
+/// The outcome for a file, with how much the candidates support it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub resolution: Resolution,
+    /// Share of the candidate weight supporting the chosen date, from 0 to 1
+    pub confidence: f64,
+    /// Human readable explanation of the choice
+    pub reason: String,
+}
+
+impl Decision {
+    fn unresolved(reason: &str) -> Self {
+        Decision {
+            resolution: Resolution::Unresolved(reason.to_string()),
+            confidence: 0.0,
+            reason: reason.to_string(),
+        }
+    }
+
+    fn rejected(rejected: &[String]) -> Self {
+        let reason = format!("implausible: {}", rejected.join(", "));
+        Decision {
+            resolution: Resolution::Rejected(reason.clone()),
+            confidence: 0.0,
+            reason,
+        }
+    }
+}
+
+/// Confidence weight of each kind of source. The file time is reset by
+/// copies too easily to carry weight by default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Weights(HashMap<SourceKind, f64>);
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights(HashMap::from([
+            (SourceKind::Filename, 1.0),
+            (SourceKind::Exif, 1.0),
+            (SourceKind::Container, 1.0),
+            (SourceKind::Sidecar, 1.0),
+            (SourceKind::Takeout, 1.0),
+            (SourceKind::Directory, 0.3),
+            (SourceKind::Filesystem, 0.0),
+        ]))
+    }
+}
+
+impl Weights {
+    pub fn get(&self, kind: SourceKind) -> f64 {
+        self.0.get(&kind).copied().unwrap_or(0.0)
+    }
+
+    pub fn set(&mut self, kind: SourceKind, weight: f64) {
+        self.0.insert(kind, weight);
+    }
+}
+
+/// How the date to keep is chosen among the candidates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// The best scoring group of agreeing candidates, the earlier on a tie
+    #[default]
+    Weighted,
+    /// The filename date, whenever there is one
+    PreferFilename,
+    /// The EXIF date, even an implausible one, then any other metadata
+    /// date, the filename only filling in when there is none
+    PreferExif,
+    /// The earliest filename or metadata date
+    Earliest,
+    /// The latest filename or metadata date
+    Latest,
+    /// The metadata date unless it is implausible, e.g. from a camera whose
+    /// clock was reset, then the filename date
+    ExifIfPlausible,
+    /// Never replace an existing date, only add a missing one to metadata
+    FillMissingOnly,
+}
+
+impl Policy {
+    const NAMES: &[(Policy, &str)] = &[
+        (Policy::Weighted, "weighted"),
+        (Policy::PreferFilename, "prefer-filename"),
+        (Policy::PreferExif, "prefer-exif"),
+        (Policy::Earliest, "earliest"),
+        (Policy::Latest, "latest"),
+        (Policy::ExifIfPlausible, "exif-if-plausible"),
+        (Policy::FillMissingOnly, "fill-missing-only"),
+    ];
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Policy::NAMES
+            .iter()
+            .find(|(_, name)| *name == s)
+            .map(|(policy, _)| *policy)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Policy::NAMES.iter().map(|(_, n)| *n).collect();
+                format!("Unknown policy '{}', expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = Policy::NAMES.iter().find(|(p, _)| p == self).map_or("", |(_, n)| *n);
+        write!(f, "{}", name)
+    }
+}
+
+/// Bounds outside which dates are rejected as implausible, such as those of
+/// a reset camera clock
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plausibility {
+    /// Dates before this year are rejected
+    pub min_year: i32,
+    /// How far in the future dates may be, for clocks running slightly ahead
+    pub future_margin: Duration,
+    /// Release year of camera models, before which their files cannot date
+    pub camera_years: HashMap<String, i32>,
+}
+
+impl Default for Plausibility {
+    fn default() -> Self {
+        Plausibility {
+            min_year: 1995,
+            future_margin: Duration::days(1),
+            camera_years: HashMap::new(),
+        }
+    }
+}
+
+impl Plausibility {
+    /// Why a candidate is implausible for a file taken with the given camera
+    /// model, or None when it is plausible
+    pub fn check(
+        &self,
+        candidate: &Candidate,
+        instant: DateTime<FixedOffset>,
+        camera: Option<&str>,
+    ) -> Option<String> {
+        if candidate.malformed {
+            return Some("not a date".to_string());
+        }
+        let year = candidate.datetime.year();
+        if year < self.min_year {
+            return Some(format!("before {}", self.min_year));
+        }
+        if let Some((model, release)) = camera.and_then(|m| self.camera_years.get_key_value(m))
+            && year < *release
+        {
+            return Some(format!("before the {} was released in {}", model, release));
+        }
+        if instant > Utc::now() + self.future_margin {
+            return Some("in the future".to_string());
+        }
+        None
+    }
+}
+
+/// Scores closer than this are a tie, settled in favour of the earlier date:
+/// dates only ever get later by being copied or edited
+const TIE_MARGIN: f64 = 0.25;
+
+/// Chooses among the candidates collected for a file.
+///
+/// Candidates within the tolerance of each other agree and form a group.
+/// Each group is scored with the weights of the kinds of sources in it, plus
+/// that of the directory when its range covers the group, and the best group
+/// wins. Its share of the total score of all groups is the confidence of the
+/// decision.
+/// Dates are compared as points in time, so candidates with an offset are
+/// compared correctly against local filename dates.
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    /// Time zone of the dates that carry no offset
+    pub timezone: TimeZoneSpec,
+    pub policy: Policy,
+    pub weights: Weights,
+    /// Changes with a lower confidence are reported but not applied
+    pub min_confidence: f64,
+    /// Largest difference between points in time that agree
+    pub tolerance: Duration,
+    pub plausibility: Plausibility,
+    /// Camera model of the file, for the plausibility of its dates
+    pub camera: Option<String>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver {
+            timezone: TimeZoneSpec::default(),
+            policy: Policy::default(),
+            weights: Weights::default(),
+            min_confidence: 0.5,
+            tolerance: Duration::minutes(1),
+            plausibility: Plausibility::default(),
+            camera: None,
+        }
+    }
+}
+
+/// Candidates that agree with each other
+struct Group<'a> {
+    members: Vec<&'a Candidate>,
+    /// The member whose date is used
+    chosen: &'a Candidate,
+    instant: DateTime<FixedOffset>,
+    /// Whether the directory range covers the group
+    in_directory: bool,
+    score: f64,
+}
+
+impl Resolver {
+    /// Decide what to do given all the candidates for a file. `has_metadata`
+    /// tells whether the file carries embedded metadata at all.
+    pub fn resolve(&self, candidates: &[Candidate], has_metadata: bool) -> Decision {
+        let has_basis = |candidates: &[Candidate]| {
+            candidates
+                .iter()
+                .any(|c| c.kind == SourceKind::Filename || c.kind == SourceKind::Directory)
+        };
+        if !has_basis(candidates) {
+            return Decision::unresolved("no date in the filename");
+        }
+        // Shown as the date being replaced even when it is rejected
+        let replaced = candidates
+            .iter()
+            .find(|c| {
+                !c.malformed
+                    && if has_metadata {
+                        c.kind.is_stored()
+                    } else {
+                        c.kind == SourceKind::Filesystem
+                    }
+            })
+            .map(|c| c.instant(&self.timezone));
+        let (candidates, rejected) = self.plausible(candidates);
+        if !has_basis(&candidates) {
+            return Decision::rejected(&rejected);
+        }
+        let candidates = candidates.as_slice();
+        let directory = candidates.iter().find(|c| c.kind == SourceKind::Directory);
+        // The date that a change would replace: the metadata date, or the
+        // file time of files without metadata
+        let existing = if has_metadata {
+            candidates.iter().find(|c| c.kind.is_stored())
+        } else {
+            candidates.iter().find(|c| c.kind == SourceKind::Filesystem)
+        };
+
+        let groups = self.groups(candidates, directory);
+        let Some(target) = self.pick(candidates, existing, &groups) else {
+            return Decision::unresolved("no candidate dates");
+        };
+        let Some(chosen) = groups
+            .iter()
+            .find(|g| g.members.iter().any(|m| std::ptr::eq(*m, target)))
+        else {
+            return Decision::unresolved("no candidate dates");
+        };
+        // Every group counts against the others, and so does a directory
+        // agreeing with none of them
+        let mut total: f64 = groups.iter().map(|g| g.score).sum();
+        if directory.is_some() && !groups.iter().any(|g| g.in_directory) {
+            total += self.weights.get(SourceKind::Directory);
+        }
+        let confidence = if total > 0.0 { chosen.score / total } else { 0.0 };
+        let mut reason = self.reason(chosen, &groups, directory);
+        if !rejected.is_empty() {
+            reason.push_str(&format!("; rejected as implausible: {}", rejected.join(", ")));
+        }
+        if self.policy != Policy::Weighted {
+            reason = format!("policy {}: {}", self.policy, reason);
+        }
+
+        let to = chosen.instant;
+        let resolution = match existing {
+            Some(existing) if chosen.members.iter().any(|m| std::ptr::eq(*m, existing)) => {
+                Resolution::Unchanged
+            }
+            // Points in time are chosen over whole days, so a day left alone
+            // has no EXIF date or file time on it to take the time of day from
+            _ if chosen.chosen.until.is_some() => Resolution::Unresolved(format!(
+                "time of day unknown: {} only gives the date {}",
+                chosen.chosen.label,
+                chosen.chosen.datetime.date()
+            )),
+            _ if confidence < self.min_confidence => Resolution::Uncertain { to },
+            _ if has_metadata => Resolution::WriteMetadata {
+                from: replaced,
+                to,
+            },
+            _ => Resolution::SetFileTime { from: replaced, to },
+        };
+        Decision {
+            resolution,
+            confidence,
+            reason,
+        }
+    }
+
+    /// The candidates within the plausibility bounds, and a description of
+    /// the others. Directories name periods rather than dates and are kept,
+    /// and so are well-formed EXIF dates under `prefer-exif`, which trusts
+    /// them as they are.
+    fn plausible(&self, candidates: &[Candidate]) -> (Vec<Candidate>, Vec<String>) {
+        let mut kept = Vec::new();
+        let mut rejected = Vec::new();
+        for candidate in candidates {
+            let trusted = self.policy == Policy::PreferExif
+                && candidate.kind == SourceKind::Exif
+                && !candidate.malformed;
+            let why = if candidate.kind == SourceKind::Directory || trusted {
+                None
+            } else {
+                let instant = candidate.instant(&self.timezone);
+                self.plausibility.check(candidate, instant, self.camera.as_deref())
+            };
+            match why {
+                Some(why) if candidate.malformed => {
+                    rejected.push(format!("{} ({})", candidate.label, why))
+                }
+                Some(why) => rejected.push(format!(
+                    "{} {} ({})",
+                    candidate.label, candidate.datetime, why
+                )),
+                None => kept.push(candidate.clone()),
+            }
+        }
+        (kept, rejected)
+    }
+
+    /// The candidate whose date the policy settles on
+    fn pick<'a>(
+        &self,
+        candidates: &'a [Candidate],
+        existing: Option<&'a Candidate>,
+        groups: &[Group<'a>],
+    ) -> Option<&'a Candidate> {
+        let filename = candidates.iter().find(|c| c.kind == SourceKind::Filename);
+        let metadata = existing.filter(|e| e.kind.is_stored());
+        let weighted = || self.best(groups).map(|g| g.chosen);
+        // Dates describing the capture itself
+        let mut captures: Vec<&Candidate> = candidates
+            .iter()
+            .filter(|c| c.kind == SourceKind::Filename || c.kind.is_metadata())
+            .collect();
+        captures.sort_by_key(|c| c.instant(&self.timezone));
+        match self.policy {
+            Policy::Weighted => weighted(),
+            Policy::PreferFilename => filename.or_else(weighted),
+            Policy::PreferExif => candidates
+                .iter()
+                .find(|c| c.kind == SourceKind::Exif)
+                .or(metadata)
+                .or(filename)
+                .or_else(weighted),
+            Policy::Earliest => captures.first().copied().or_else(weighted),
+            Policy::Latest => captures.last().copied().or_else(weighted),
+            // Implausible dates are rejected before any policy applies
+            Policy::ExifIfPlausible => metadata.or(filename).or_else(weighted),
+            Policy::FillMissingOnly => existing.or(filename).or_else(weighted),
+        }
+    }
+
+    /// Group the candidates that agree, sorted by date. Candidates naming a
+    /// whole day, such as date-only filenames, join a group on that day, or
+    /// form their own so they can fill in missing dates. Directories naming
+    /// longer periods only support the groups they cover.
+    fn groups<'a>(
+        &self,
+        candidates: &'a [Candidate],
+        directory: Option<&'a Candidate>,
+    ) -> Vec<Group<'a>> {
+        let (periods, mut points): (Vec<&Candidate>, Vec<&Candidate>) = candidates
+            .iter()
+            .filter(|c| c.kind != SourceKind::Directory || is_single_day(c))
+            .partition(|c| c.until.is_some());
+        points.sort_by_key(|c| c.instant(&self.timezone));
+        let mut groups: Vec<Vec<&Candidate>> = Vec::new();
+        for candidate in points {
+            let instant = candidate.instant(&self.timezone);
+            match groups.last_mut() {
+                Some(group)
+                    if instant - group.last().unwrap().instant(&self.timezone)
+                        <= self.tolerance =>
+                {
+                    group.push(candidate)
+                }
+                _ => groups.push(vec![candidate]),
+            }
+        }
+        for period in periods {
+            let weight = |group: &Vec<&Candidate>| -> f64 {
+                group.iter().map(|c| self.weights.get(c.kind)).sum()
+            };
+            let covered = groups
+                .iter_mut()
+                .filter(|group| group.iter().any(|c| period.covers(c.datetime)))
+                .fold(None::<&mut Vec<&Candidate>>, |best, group| match best {
+                    Some(b) if weight(b) >= weight(group) => Some(b),
+                    _ => Some(group),
+                });
+            match covered {
+                Some(group) => group.push(period),
+                None => groups.push(vec![period]),
+            }
+        }
+        let mut groups: Vec<Group> = groups
+            .into_iter()
+            .map(|members| {
+                // Sources are queried in order of trust, so the first of the
+                // heaviest members gives the date, points in time before days
+                let rank = |c: &Candidate| (c.until.is_none(), self.weights.get(c.kind));
+                let chosen = candidates
+                    .iter()
+                    .filter(|c| members.iter().any(|m| std::ptr::eq(*m, *c)))
+                    .fold(None::<&Candidate>, |best, c| match best {
+                        Some(b) if rank(b) >= rank(c) => Some(b),
+                        _ => Some(c),
+                    })
+                    .unwrap();
+                let instant = chosen.instant(&self.timezone);
+                let in_directory =
+                    directory.is_some_and(|d| d.covers(instant.naive_local()));
+                let mut kinds: Vec<SourceKind> = members.iter().map(|m| m.kind).collect();
+                if in_directory {
+                    kinds.push(SourceKind::Directory);
+                }
+                kinds.sort();
+                kinds.dedup();
+                Group {
+                    score: kinds.iter().map(|k| self.weights.get(*k)).sum(),
+                    members,
+                    chosen,
+                    instant,
+                    in_directory,
+                }
+            })
+            .collect();
+        groups.sort_by_key(|g| g.instant);
+        groups
+    }
+
+    /// The best scoring group, the earliest one among ties
+    fn best<'g, 'a>(&self, groups: &'g [Group<'a>]) -> Option<&'g Group<'a>> {
+        let top = groups.iter().map(|g| g.score).fold(f64::NEG_INFINITY, f64::max);
+        groups.iter().find(|g| g.score > top - TIE_MARGIN)
+    }
+
+    fn reason(&self, best: &Group, groups: &[Group], directory: Option<&Candidate>) -> String {
+        let labels = |group: &Group| {
+            group
+                .members
+                .iter()
+                .map(|m| m.label.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut reason = format!("{} ({})", best.chosen.label, best.instant);
+        if best.members.len() > 1 {
+            reason.push_str(&format!(", agreeing: {}", labels(best)));
+        }
+        let others: Vec<String> = groups
+            .iter()
+            .filter(|g| !std::ptr::eq(*g, best))
+            .map(labels)
+            .collect();
+        if !others.is_empty() {
+            reason.push_str(&format!("; disagreeing: {}", others.join(", ")));
+        }
+        if let Some(directory) = directory
+            && !directory.covers(best.instant.naive_local())
+        {
+            reason.push_str(&format!("; outside the dates of the {}", directory.label));
+        }
+        reason
+    }
+}
+
+fn is_single_day(candidate: &Candidate) -> bool {
+    candidate
+        .until
+        .is_some_and(|until| until.date() == candidate.datetime.date())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveDateTime};
+
+    fn at(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn candidate(kind: SourceKind, date: &str) -> Candidate {
+        Candidate::new(at(date), kind, format!("{:?}", kind))
+    }
+
+    fn directory(first: &str, last: &str) -> Candidate {
+        let day = |d| NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap();
+        Candidate::new(day(first).into(), SourceKind::Directory, "directory (test)")
+            .with_until(day(last).and_hms_opt(23, 59, 59))
+    }
+
+    fn resolver() -> Resolver {
+        Resolver {
+            timezone: "+00:00".parse().unwrap(),
+            ..Resolver::default()
+        }
+    }
+
+    fn instant(date: &str) -> DateTime<FixedOffset> {
+        format!("{}:00+00:00", date.replace(' ', "T")).parse().unwrap()
+    }
+
+    #[test]
+    fn earlier_filename_wins_a_tie() {
+        let candidates = [
+            candidate(SourceKind::Filename, "2019-08-02 10:00"),
+            candidate(SourceKind::Exif, "2019-08-03 10:00"),
+        ];
+        let decision = resolver().resolve(&candidates, true);
+        assert_eq!(
+            decision.resolution,
+            Resolution::WriteMetadata {
+                from: Some(instant("2019-08-03 10:00")),
+                to: instant("2019-08-02 10:00"),
+            }
+        );
+        assert_eq!(decision.confidence, 0.5);
+        // A later filename leaves the metadata alone
+        let candidates = [
+            candidate(SourceKind::Filename, "2019-08-04 10:00"),
+            candidate(SourceKind::Exif, "2019-08-03 10:00"),
+            candidate(SourceKind::Filesystem, "2019-08-04 10:00"),
+        ];
+        assert_eq!(resolver().resolve(&candidates, true).resolution, Resolution::Unchanged);
+    }
+
+    #[test]
+    fn agreement_outweighs_a_single_source() {
+        let candidates = [
+            candidate(SourceKind::Filename, "2019-08-02 10:00"),
+            candidate(SourceKind::Exif, "2019-08-01 10:00"),
+            candidate(SourceKind::Exif, "2019-08-02 10:00"),
+            candidate(SourceKind::Takeout, "2019-08-02 10:00"),
+        ];
+        let decision = resolver().resolve(&candidates, true);
+        assert_eq!(
+            decision.resolution,
+            Resolution::WriteMetadata {
+                from: Some(instant("2019-08-01 10:00")),
+                to: instant("2019-08-02 10:00"),
+            }
+        );
+        assert_eq!(decision.confidence, 0.75);
+        assert!(decision.reason.contains("disagreeing"), "{}", decision.reason);
+    }
+
+    #[test]
+    fn takeout_dates_are_written_not_kept() {
+        let candidates = [
+            candidate(SourceKind::Filename, "2019-08-02 10:00"),
+            candidate(SourceKind::Takeout, "2019-08-02 10:00"),
+        ];
+        let decision = resolver().resolve(&candidates, true);
+        assert_eq!(
+            decision.resolution,
+            Resolution::WriteMetadata {
+                from: None,
+                to: instant("2019-08-02 10:00"),
+            }
+        );
+        assert_eq!(decision.confidence, 1.0);
+    }
+
+    #[test]
+    fn directory_lowers_confidence_outside_its_range() {
+        let filename = candidate(SourceKind::Filename, "2019-08-02 10:00");
+        let exif = candidate(SourceKind::Exif, "2019-08-03 10:00");
+        let candidates = [filename.clone(), exif.clone(), directory("2019-07-01", "2019-07-31")];
+        let decision = resolver().resolve(&candidates, true);
+        assert_eq!(
+            decision.resolution,
+            Resolution::Uncertain {
+                to: instant("2019-08-02 10:00")
+            }
+        );
+        let candidates = [filename, exif, directory("2019-08-01", "2019-08-31")];
+        assert!(matches!(
+            resolver().resolve(&candidates, true).resolution,
+            Resolution::WriteMetadata { .. }
+        ));
+    }
+
+    #[test]
+    fn directory_fills_in_missing_dates() {
+        // Metadata without a date gets the file time on the directory day
+        let day = [
+            directory("2019-07-14", "2019-07-14"),
+            candidate(SourceKind::Filesystem, "2019-07-14 18:30"),
+        ];
+        assert_eq!(
+            resolver().resolve(&day, true).resolution,
+            write(None, "2019-07-14 18:30")
+        );
+        let mtime = candidate(SourceKind::Filesystem, "2024-01-01 10:00");
+        // A month is too vague to set a date from
+        let month = [directory("2019-07-01", "2019-07-31"), mtime];
+        assert_eq!(resolver().resolve(&month, false).resolution, Resolution::Unchanged);
+        // A metadata date within the range is kept
+        let exif = candidate(SourceKind::Exif, "2019-07-20 10:00");
+        let month = [directory("2019-07-01", "2019-07-31"), exif];
+        let decision = resolver().resolve(&month, true);
+        assert_eq!(decision.resolution, Resolution::Unchanged);
+        assert_eq!(decision.confidence, 1.0);
+    }
+
+    fn with_policy(policy: &str) -> Resolver {
+        Resolver {
+            policy: policy.parse().unwrap(),
+            ..resolver()
+        }
+    }
+
+    fn write(from: Option<&str>, to: &str) -> Resolution {
+        Resolution::WriteMetadata {
+            from: from.map(instant),
+            to: instant(to),
+        }
+    }
+
+    /// A filename date and a metadata date, in either order
+    fn pair(filename: &str, exif: &str) -> [Candidate; 2] {
+        [
+            candidate(SourceKind::Filename, filename),
+            candidate(SourceKind::Exif, exif),
+        ]
+    }
+
+    const EARLY: &str = "2019-08-02 10:00";
+    const LATE: &str = "2019-08-03 10:00";
+
+    #[test]
+    fn prefer_filename_writes_whenever_the_filename_differs() {
+        let r = with_policy("prefer-filename");
+        assert_eq!(r.resolve(&pair(EARLY, LATE), true).resolution, write(Some(LATE), EARLY));
+        assert_eq!(r.resolve(&pair(LATE, EARLY), true).resolution, write(Some(EARLY), LATE));
+        assert_eq!(r.resolve(&pair(EARLY, EARLY), true).resolution, Resolution::Unchanged);
+    }
+
+    #[test]
+    fn prefer_exif_writes_only_without_a_metadata_date() {
+        let r = with_policy("prefer-exif");
+        let mtime = [
+            candidate(SourceKind::Filename, EARLY),
+            candidate(SourceKind::Filesystem, LATE),
+        ];
+        assert_eq!(
+            r.resolve(&mtime, false).resolution,
+            Resolution::SetFileTime {
+                from: Some(instant(LATE)),
+                to: instant(EARLY)
+            }
+        );
+        assert_eq!(r.resolve(&pair(EARLY, LATE), true).resolution, Resolution::Unchanged);
+        assert_eq!(r.resolve(&pair(LATE, EARLY), true).resolution, Resolution::Unchanged);
+        let filename = [candidate(SourceKind::Filename, EARLY)];
+        assert_eq!(r.resolve(&filename, true).resolution, write(None, EARLY));
+    }
+
+    #[test]
+    fn earliest_writes_an_earlier_filename() {
+        let r = with_policy("earliest");
+        assert_eq!(r.resolve(&pair(EARLY, LATE), true).resolution, write(Some(LATE), EARLY));
+        assert_eq!(r.resolve(&pair(LATE, EARLY), true).resolution, Resolution::Unchanged);
+    }
+
+    #[test]
+    fn latest_writes_a_later_filename() {
+        let r = with_policy("latest");
+        assert_eq!(r.resolve(&pair(EARLY, LATE), true).resolution, Resolution::Unchanged);
+        assert_eq!(r.resolve(&pair(LATE, EARLY), true).resolution, write(Some(EARLY), LATE));
+    }
+
+    #[test]
+    fn exif_if_plausible_writes_over_reset_clocks() {
+        let r = with_policy("exif-if-plausible");
+        assert_eq!(r.resolve(&pair(EARLY, LATE), true).resolution, Resolution::Unchanged);
+        assert_eq!(r.resolve(&pair(LATE, EARLY), true).resolution, Resolution::Unchanged);
+        let reset = "1970-01-01 00:00";
+        assert_eq!(r.resolve(&pair(LATE, reset), true).resolution, write(Some(reset), LATE));
+        let future = "2999-01-01 00:00";
+        assert_eq!(r.resolve(&pair(EARLY, future), true).resolution, write(Some(future), EARLY));
+    }
+
+    #[test]
+    fn prefer_exif_keeps_implausible_exif_dates() {
+        let reset = "1970-01-01 00:00";
+        let prefer_exif = with_policy("prefer-exif").resolve(&pair(LATE, reset), true);
+        assert_eq!(prefer_exif.resolution, Resolution::Unchanged);
+        let if_plausible = with_policy("exif-if-plausible").resolve(&pair(LATE, reset), true);
+        assert_eq!(if_plausible.resolution, write(Some(reset), LATE));
+    }
+
+    #[test]
+    fn rejects_implausible_dates() {
+        let reset = "1970-01-01 00:00";
+        let decision = resolver().resolve(&[candidate(SourceKind::Filename, reset)], true);
+        assert_eq!(
+            decision.resolution,
+            Resolution::Rejected("implausible: Filename 1970-01-01 00:00:00 (before 1995)".into())
+        );
+        // Junk in the metadata counts as no date
+        let junk =
+            Candidate::malformed("0000:00:00 00:00:00", SourceKind::Exif, "EXIF DateTimeOriginal");
+        let candidates = [candidate(SourceKind::Filename, EARLY), junk];
+        let decision = resolver().resolve(&candidates, true);
+        assert_eq!(decision.resolution, write(None, EARLY));
+        let reason = decision.reason;
+        assert!(reason.contains("'0000:00:00 00:00:00' (not a date)"), "{}", reason);
+        // Dates before the release of the camera
+        let mut r = resolver();
+        r.plausibility.camera_years.insert("EOS R5".into(), 2020);
+        r.camera = Some("EOS R5".into());
+        let candidates = pair(EARLY, "2022-05-01 10:00");
+        assert!(matches!(r.resolve(&candidates, true).resolution, Resolution::Rejected(_)));
+        r.plausibility.min_year = 2030;
+        r.camera = None;
+        let decision = r.resolve(&pair("2999-01-01 10:00", EARLY), true);
+        assert!(matches!(
+            decision.resolution,
+            Resolution::Rejected(reason) if reason.contains("in the future")
+        ));
+    }
+
+    #[test]
+    fn fill_missing_only_never_replaces_a_date() {
+        let r = with_policy("fill-missing-only");
+        assert_eq!(r.resolve(&pair(EARLY, LATE), true).resolution, Resolution::Unchanged);
+        assert_eq!(r.resolve(&pair(LATE, EARLY), true).resolution, Resolution::Unchanged);
+        let filename = [candidate(SourceKind::Filename, EARLY)];
+        assert_eq!(r.resolve(&filename, true).resolution, write(None, EARLY));
+        // Without metadata, the file time is the existing date
+        let mtime = [
+            candidate(SourceKind::Filename, EARLY),
+            candidate(SourceKind::Filesystem, LATE),
+        ];
+        assert_eq!(r.resolve(&mtime, false).resolution, Resolution::Unchanged);
+    }
+
+    #[test]
+    fn policies_round_trip() {
+        for (policy, name) in Policy::NAMES {
+            assert_eq!(name.parse::<Policy>(), Ok(*policy));
+            assert_eq!(policy.to_string(), *name);
+        }
+        assert!("newest".parse::<Policy>().is_err());
+    }
+
+    #[test]
+    fn tolerance_absorbs_small_differences() {
+        let candidates = pair("2019-08-02 10:00", "2019-08-02 10:04");
+        assert!(matches!(
+            resolver().resolve(&candidates, true).resolution,
+            Resolution::WriteMetadata { .. }
+        ));
+        let r = Resolver {
+            tolerance: Duration::minutes(5),
+            ..resolver()
+        };
+        assert_eq!(r.resolve(&candidates, true).resolution, Resolution::Unchanged);
+    }
+
+    #[test]
+    fn date_only_filename_agrees_with_the_same_day() {
+        let day = |date: &str| {
+            let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+            Candidate::new(date.into(), SourceKind::Filename, "filename (whatsapp-image)")
+                .with_until(date.and_hms_opt(23, 59, 59))
+        };
+        for policy in ["weighted", "prefer-filename", "earliest", "latest"] {
+            let candidates = [day("2019-08-03"), candidate(SourceKind::Exif, LATE)];
+            let decision = with_policy(policy).resolve(&candidates, true);
+            assert_eq!(decision.resolution, Resolution::Unchanged, "{}", policy);
+            assert_eq!(decision.confidence, 1.0, "{}", policy);
+        }
+        // Another day and no time on this one: midnight is not invented
+        let candidates = [day("2019-08-02"), candidate(SourceKind::Exif, LATE)];
+        assert!(matches!(
+            resolver().resolve(&candidates, true).resolution,
+            Resolution::Unresolved(reason) if reason.starts_with("time of day unknown")
+        ));
+        // The file time on that day gives the time
+        let candidates = [
+            day("2019-08-02"),
+            candidate(SourceKind::Exif, LATE),
+            candidate(SourceKind::Filesystem, "2019-08-02 17:45"),
+        ];
+        assert_eq!(
+            resolver().resolve(&candidates, true).resolution,
+            write(Some(LATE), "2019-08-02 17:45")
+        );
+        let candidates = [day("2019-08-02"), candidate(SourceKind::Filesystem, "2019-08-02 17:45")];
+        assert_eq!(resolver().resolve(&candidates, false).resolution, Resolution::Unchanged);
+        // Nor is the time of day of a file time on another day taken
+        let candidates = [day("2019-08-02"), candidate(SourceKind::Filesystem, "2024-01-05 03:12")];
+        for has_metadata in [false, true] {
+            assert!(matches!(
+                resolver().resolve(&candidates, has_metadata).resolution,
+                Resolution::Unresolved(reason) if reason.starts_with("time of day unknown")
+            ));
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Dates of undated files estimated from the numbering of their neighbours,
+//! such as `DSC_0041.JPG` between a dated `DSC_0040.JPG` and `DSC_0045.JPG`.
+
+use chrono::{DateTime, Duration, FixedOffset};
+use regex::Regex;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Confidence of an interpolated date: a guess, however close the neighbours
+pub const INTERPOLATED_CONFIDENCE: f64 = 0.25;
+
+/// The prefix and number of a file named by a camera counter: a short
+/// letter prefix and a number, e.g. DSC_0041, IMG_1234, DSCN0001, _DSC0041
+/// or P1010001
+pub fn sequence_number(path: &Path) -> Option<(String, u64)> {
+    static SEQUENCE: OnceLock<Regex> = OnceLock::new();
+    let regex = SEQUENCE.get_or_init(|| {
+        Regex::new(r"^(?P<prefix>_?[A-Za-z]{1,5}[_-]?)(?P<number>\d{3,9})$").expect("valid regex")
+    });
+    let stem = path.file_stem()?.to_str()?;
+    let caps = regex.captures(stem)?;
+    Some((caps["prefix"].to_string(), caps["number"].parse().ok()?))
+}
+
+/// A file of a sequence whose date is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Known<T> {
+    pub number: u64,
+    pub date: DateTime<FixedOffset>,
+    pub file: T,
+}
+
+/// Estimate the date of the file numbered `number` from the closest known
+/// files on either side, in proportion to its position between them. There
+/// is no estimate when they are out of order or further apart than
+/// `max_span`, as the numbering then says little about the date.
+pub fn interpolate<T>(
+    known: &[Known<T>],
+    number: u64,
+    max_span: Duration,
+) -> Option<(DateTime<FixedOffset>, &Known<T>, &Known<T>)> {
+    let before = known
+        .iter()
+        .filter(|k| k.number < number)
+        .max_by_key(|k| k.number)?;
+    let after = known
+        .iter()
+        .filter(|k| k.number > number)
+        .min_by_key(|k| k.number)?;
+    let span = after.date - before.date;
+    if span < Duration::zero() || span > max_span {
+        return None;
+    }
+    let millis = span.num_milliseconds() as i128 * (number - before.number) as i128
+        / (after.number - before.number) as i128;
+    Some((before.date + Duration::milliseconds(millis as i64), before, after))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_camera_counters() {
+        let number = |name: &str| sequence_number(Path::new(name));
+        assert_eq!(number("DSC_0041.JPG"), Some(("DSC_".to_string(), 41)));
+        assert_eq!(number("P1010001.JPG"), Some(("P".to_string(), 1010001)));
+        assert_eq!(number("_DSC0041.NEF"), Some(("_DSC".to_string(), 41)));
+        assert_eq!(number("IMG_20190802_100000.jpg"), None);
+        assert_eq!(number("holiday.jpg"), None);
+        assert_eq!(number("IMG_1234 (1).jpg"), None);
+    }
+
+    #[test]
+    fn interpolates_between_neighbours() {
+        let known = |number, date: &str| Known {
+            number,
+            date: DateTime::parse_from_rfc3339(date).unwrap(),
+            file: number,
+        };
+        let files = [
+            known(40, "2019-08-02T10:00:00+02:00"),
+            known(45, "2019-08-02T10:05:00+02:00"),
+            known(50, "2019-08-01T10:00:00+02:00"),
+        ];
+        let day = Duration::days(1);
+        let (date, before, after) = interpolate(&files, 42, day).unwrap();
+        assert_eq!(date.to_rfc3339(), "2019-08-02T10:02:00+02:00");
+        assert_eq!((before.file, after.file), (40, 45));
+        // Out of order neighbours, or only one side known
+        assert_eq!(interpolate(&files, 47, day), None);
+        assert_eq!(interpolate(&files, 39, day), None);
+        assert_eq!(interpolate(&files, 42, Duration::minutes(1)), None);
+    }
+}
@@ -0,0 +1,570 @@
+use crate::patterns::PatternRegistry;
+use crate::timezone::{self, TimeZoneSpec};
+use crate::writer;
+use crate::xmp::{self, SidecarNaming};
+use crate::{heif, mp4, png, takeout};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use exif::{Exif, In, Reader, Tag, Value};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Where a candidate date was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SourceKind {
+    Filename,
+    Exif,
+    /// Container metadata other than EXIF: MP4 boxes, PNG text chunks
+    Container,
+    /// An XMP sidecar next to the file
+    Sidecar,
+    /// A Google Takeout JSON file next to the file
+    Takeout,
+    /// The names of the directories containing the file
+    Directory,
+    Filesystem,
+}
+
+impl SourceKind {
+    pub const ALL: &[SourceKind] = &[
+        SourceKind::Filename,
+        SourceKind::Exif,
+        SourceKind::Container,
+        SourceKind::Sidecar,
+        SourceKind::Takeout,
+        SourceKind::Directory,
+        SourceKind::Filesystem,
+    ];
+
+    /// Name used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            SourceKind::Filename => "filename",
+            SourceKind::Exif => "exif",
+            SourceKind::Container => "container",
+            SourceKind::Sidecar => "sidecar",
+            SourceKind::Takeout => "takeout",
+            SourceKind::Directory => "directory",
+            SourceKind::Filesystem => "filesystem",
+        }
+    }
+
+    /// Whether the date is part of the file metadata, embedded or in a file
+    /// next to it
+    pub fn is_metadata(self) -> bool {
+        matches!(
+            self,
+            SourceKind::Exif | SourceKind::Container | SourceKind::Sidecar | SourceKind::Takeout
+        )
+    }
+
+    /// Whether the date is stored in or beside the file, and so replaced when
+    /// metadata is written. Takeout JSON only describes the file.
+    pub fn is_stored(self) -> bool {
+        matches!(self, SourceKind::Exif | SourceKind::Container | SourceKind::Sidecar)
+    }
+}
+
+impl FromStr for SourceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        SourceKind::ALL
+            .iter()
+            .find(|k| k.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = SourceKind::ALL.iter().map(|k| k.name()).collect();
+                format!("Unknown source '{}', expected one of: {}", s, names.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A date found for a file, with its provenance
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// Local time as found in the source
+    pub datetime: NaiveDateTime,
+    /// Offset from UTC, when the source records it
+    pub offset: Option<FixedOffset>,
+    pub kind: SourceKind,
+    /// Human readable origin, e.g. the pattern or tag name
+    pub label: String,
+    /// For a source naming a whole period, such as a month, its last moment;
+    /// `datetime` is then its first
+    pub until: Option<NaiveDateTime>,
+    /// The source holds a value that is not a date, such as the
+    /// `0000:00:00 00:00:00` of cameras without a clock; `datetime` is then
+    /// meaningless
+    pub malformed: bool,
+}
+
+impl Candidate {
+    pub fn new(datetime: NaiveDateTime, kind: SourceKind, label: impl Into<String>) -> Self {
+        Candidate {
+            datetime,
+            offset: None,
+            kind,
+            label: label.into(),
+            until: None,
+            malformed: false,
+        }
+    }
+
+    /// A value that should have been a date but is not, labelled with it
+    pub fn malformed(value: &str, kind: SourceKind, label: impl Into<String>) -> Self {
+        let epoch = NaiveDate::from_ymd_opt(0, 1, 1).unwrap().into();
+        Candidate {
+            malformed: true,
+            ..Candidate::new(epoch, kind, format!("{} '{}'", label.into(), value))
+        }
+    }
+
+    pub fn with_offset(mut self, offset: Option<FixedOffset>) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    pub fn with_until(mut self, until: Option<NaiveDateTime>) -> Self {
+        self.until = until;
+        self
+    }
+
+    /// Whether a local time falls within the period of this candidate, or
+    /// on its day for candidates without a period
+    pub fn covers(&self, local: NaiveDateTime) -> bool {
+        match self.until {
+            Some(until) => self.datetime <= local && local <= until,
+            None => self.datetime.date() == local.date(),
+        }
+    }
+
+    /// The point in time of this candidate, interpreting it in the given time
+    /// zone when it has no offset of its own
+    pub fn instant(&self, tz: &TimeZoneSpec) -> DateTime<FixedOffset> {
+        match self.offset {
+            Some(offset) => timezone::with_offset(self.datetime, offset),
+            None => tz.localize(self.datetime),
+        }
+    }
+}
+
+/// Something that can provide candidate dates for a file
+pub trait DateSource: Send + Sync {
+    fn kind(&self) -> SourceKind;
+
+    /// Return the dates this source knows about. An empty list means the
+    /// source applies but found nothing; an error means it could not be read.
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String>;
+}
+
+/// Dates encoded in the file name
+pub struct FilenameSource {
+    registry: PatternRegistry,
+}
+
+impl FilenameSource {
+    pub fn new(registry: PatternRegistry) -> Self {
+        FilenameSource { registry }
+    }
+
+    pub fn registry(&self) -> &PatternRegistry {
+        &self.registry
+    }
+}
+
+impl DateSource for FilenameSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Filename
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        let fname = path
+            .file_name()
+            .map(|f| f.to_string_lossy())
+            .unwrap_or_default();
+        Ok(self
+            .registry
+            .match_name(&fname)
+            .map(|m| {
+                let label = format!("filename ({})", m.rule);
+                // A date without a time stands for the whole day
+                let until = match m.time {
+                    Some(_) => None,
+                    None => m.date.and_hms_nano_opt(23, 59, 59, 999_999_999),
+                };
+                Candidate::new(m.datetime(), SourceKind::Filename, label).with_until(until)
+            })
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Dates stored in the EXIF metadata
+pub struct ExifSource;
+
+/// Date tags in order of preference, with the tags holding their fraction of
+/// a second and their offset from UTC
+const EXIF_DATE_TAGS: &[(Tag, Tag, Tag)] = &[
+    (Tag::DateTimeOriginal, Tag::SubSecTimeOriginal, Tag::OffsetTimeOriginal),
+    (Tag::DateTimeDigitized, Tag::SubSecTimeDigitized, Tag::OffsetTimeDigitized),
+    (Tag::DateTime, Tag::SubSecTime, Tag::OffsetTime),
+];
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(vec) if !vec.is_empty() => {
+            let s = String::from_utf8_lossy(&vec[0]);
+            Some(s.trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Parse a date field and its optional SubSecTime companion. A value that
+/// is not a date is returned as the error.
+fn exif_datetime(exif: &Exif, tag: Tag, subsec_tag: Tag) -> Option<Result<NaiveDateTime, String>> {
+    let s = ascii_field(exif, tag).filter(|s| !s.is_empty())?;
+    let Ok(datetime) = NaiveDateTime::parse_from_str(&s, "%Y:%m:%d %H:%M:%S") else {
+        return Some(Err(s));
+    };
+    let nanos = ascii_field(exif, subsec_tag)
+        .filter(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()))
+        .map(|s| {
+            let digits: String = s.chars().take(9).collect();
+            digits.parse::<u32>().unwrap_or(0) * 10u32.pow(9 - digits.len() as u32)
+        })
+        .unwrap_or(0);
+    datetime.with_nanosecond(nanos).map(Ok)
+}
+
+/// Combine GPSDateStamp and GPSTimeStamp, which are in UTC
+fn gps_datetime(exif: &Exif) -> Option<NaiveDateTime> {
+    let date = NaiveDate::parse_from_str(&ascii_field(exif, Tag::GPSDateStamp)?, "%Y:%m:%d").ok()?;
+    let time = match &exif.get_field(Tag::GPSTimeStamp, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() == 3 => {
+            let seconds = v[2].to_f64();
+            let secs = seconds.trunc() as u32;
+            let nanos = ((seconds - seconds.trunc()) * 1e9) as u32;
+            NaiveTime::from_hms_nano_opt(v[0].to_f64() as u32, v[1].to_f64() as u32, secs, nanos)?
+        }
+        _ => return None,
+    };
+    Some(date.and_time(time))
+}
+
+/// Convert a GPS degrees, minutes, seconds field and its N/S or E/W reference
+fn gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative: char) -> Option<f64> {
+    let value = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() == 3 => {
+            v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    if !value.is_finite() {
+        return None;
+    }
+    let reference = ascii_field(exif, ref_tag).unwrap_or_default();
+    Some(if reference.starts_with(negative) { -value } else { value })
+}
+
+/// Latitude and longitude in decimal degrees, if the EXIF data has them
+pub fn exif_coordinates(exif: &Exif) -> Option<(f64, f64)> {
+    let latitude = gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, 'S')?;
+    let longitude = gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, 'W')?;
+    Some((latitude, longitude))
+}
+
+/// A non-empty text field of the EXIF data
+pub fn exif_text(exif: &Exif, tag: Tag) -> Option<String> {
+    ascii_field(exif, tag).filter(|s| !s.is_empty())
+}
+
+/// Camera model, e.g. "Canon EOS 5D"
+pub fn exif_model(exif: &Exif) -> Option<String> {
+    exif_text(exif, Tag::Model)
+}
+
+/// Read the EXIF data of a file. HEIF files go through our own box parsing
+/// so reads and writes locate the same Exif item; other containers use
+/// kamadak-exif.
+pub fn read_exif(path: &Path) -> Result<Exif, String> {
+    if writer::has_extension(path, &["heic", "heif"]) {
+        let data = fs::read(path).map_err(|e| format!("Could not open file: {}", e))?;
+        let tiff = heif::exif_tiff(&data)?;
+        return Reader::new()
+            .read_raw(tiff.to_vec())
+            .map_err(|e| format!("No EXIF data found: {}", e));
+    }
+    let fh = File::open(path).map_err(|e| format!("Could not open file: {}", e))?;
+    let mut buf_reader = BufReader::new(fh);
+    Reader::new()
+        .read_from_container(&mut buf_reader)
+        .map_err(|e| format!("No EXIF data found: {}", e))
+}
+
+/// Every date found in the EXIF data, most trusted first
+pub fn exif_candidates(exif: &Exif) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = EXIF_DATE_TAGS
+        .iter()
+        .filter_map(|(tag, subsec, offset)| {
+            let offset = ascii_field(exif, *offset).and_then(|s| timezone::parse_offset(&s));
+            let label = format!("EXIF {}", tag);
+            exif_datetime(exif, *tag, *subsec).map(|dt| match dt {
+                Ok(dt) => Candidate::new(dt, SourceKind::Exif, label).with_offset(offset),
+                Err(value) => Candidate::malformed(&value, SourceKind::Exif, label),
+            })
+        })
+        .collect();
+    if let Some(dt) = gps_datetime(exif) {
+        candidates.push(
+            Candidate::new(dt, SourceKind::Exif, "EXIF GPSDateStamp (UTC)")
+                .with_offset(FixedOffset::east_opt(0)),
+        );
+    }
+    candidates
+}
+
+impl DateSource for ExifSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Exif
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        Ok(exif_candidates(&read_exif(path)?))
+    }
+}
+
+/// Dates stored in MP4 and QuickTime boxes, or in PNG text chunks
+pub struct ContainerSource;
+
+impl ContainerSource {
+    fn png_candidates(path: &Path) -> Result<Vec<Candidate>, String> {
+        let data = fs::read(path).map_err(|e| e.to_string())?;
+        Ok(png::creation_times(&data)?
+            .into_iter()
+            .map(|(dt, offset)| {
+                Candidate::new(dt, SourceKind::Container, "PNG Creation Time").with_offset(offset)
+            })
+            .collect())
+    }
+}
+
+impl DateSource for ContainerSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Container
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        if writer::has_extension(path, &["png"]) {
+            return Self::png_candidates(path);
+        }
+        let mut fh = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+        let fields = mp4::read_fields(&mut fh)?;
+        // Text dates carry the local time and offset, so they come first
+        let order = |label: &str| match label {
+            "QuickTime com.apple.quicktime.creationdate" => 0,
+            "QuickTime ©day" => 1,
+            _ => 2,
+        };
+        let mut candidates: Vec<Candidate> = Vec::new();
+        for field in fields.iter().filter(|f| f.creation) {
+            let Some(value) = field.value else { continue };
+            if candidates.iter().any(|c| c.label == field.label) {
+                continue;
+            }
+            candidates.push(
+                Candidate::new(value.naive_local(), SourceKind::Container, field.label)
+                    .with_offset(Some(*value.offset())),
+            );
+        }
+        candidates.sort_by_key(|c| order(&c.label));
+        Ok(candidates)
+    }
+}
+
+/// Dates in the XMP sidecar of the file, under either naming. Applications
+/// reading sidecars let them override the embedded metadata, so this source
+/// is queried first.
+pub struct XmpSource;
+
+impl DateSource for XmpSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Sidecar
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        let sidecar = [SidecarNaming::Append, SidecarNaming::Replace]
+            .into_iter()
+            .map(|naming| xmp::sidecar_path(path, naming))
+            .find(|p| p.is_file())
+            .ok_or("No XMP sidecar")?;
+        let text = fs::read_to_string(&sidecar).map_err(|e| e.to_string())?;
+        Ok(xmp::read_dates(&text)
+            .into_iter()
+            .map(|(property, dt, offset)| {
+                Candidate::new(dt, SourceKind::Sidecar, format!("XMP {}", property))
+                    .with_offset(offset)
+            })
+            .collect())
+    }
+}
+
+/// The photoTakenTime of the Google Takeout JSON describing the file
+pub struct TakeoutSource;
+
+impl DateSource for TakeoutSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Takeout
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        let metadata = takeout::read(path)?;
+        Ok(metadata
+            .taken()
+            .map(|taken| {
+                Candidate::new(taken.naive_utc(), SourceKind::Takeout, "Takeout photoTakenTime")
+                    .with_offset(FixedOffset::east_opt(0))
+            })
+            .into_iter()
+            .collect())
+    }
+}
+
+/// The range of days named by the directories containing the file
+pub struct DirectorySource {
+    registry: PatternRegistry,
+}
+
+impl DirectorySource {
+    pub fn new(registry: PatternRegistry) -> Self {
+        DirectorySource { registry }
+    }
+}
+
+impl DateSource for DirectorySource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Directory
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        let Some(m) = path.parent().and_then(|dir| self.registry.match_directory(dir)) else {
+            return Ok(Vec::new());
+        };
+        let until = m.last.and_hms_nano_opt(23, 59, 59, 999_999_999);
+        let label = format!("directory ({})", m.rule);
+        Ok(vec![
+            Candidate::new(m.first.and_time(NaiveTime::MIN), SourceKind::Directory, label)
+                .with_until(until),
+        ])
+    }
+}
+
+/// The modification time of the file
+pub struct FilesystemSource;
+
+impl DateSource for FilesystemSource {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Filesystem
+    }
+
+    fn candidates(&self, path: &Path) -> Result<Vec<Candidate>, String> {
+        let meta = fs::metadata(path).map_err(|e| e.to_string())?;
+        let mtime = meta.modified().map_err(|e| e.to_string())?;
+        let local: DateTime<Local> = mtime.into();
+        Ok(vec![
+            Candidate::new(local.naive_local(), SourceKind::Filesystem, "file modification time")
+                .with_offset(Some(*local.offset())),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, ascii, tiff};
+    use exif::{Field, Rational};
+
+    fn read(fields: &[Field]) -> Exif {
+        Reader::new().read_raw(tiff(fields, false)).unwrap()
+    }
+
+    #[test]
+    fn reads_tiff_based_raw() {
+        let fields = [ascii(Tag::DateTimeOriginal, In::PRIMARY, "2023:04:05 06:07:08")];
+        let dir = TempDir::new("raw");
+        let path = dir.join("DSC_0001.NEF");
+        fs::write(&path, tiff(&fields, false)).unwrap();
+        let candidates = ExifSource.candidates(&path).unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].label, "EXIF DateTimeOriginal");
+        assert_eq!(candidates[0].datetime.to_string(), "2023-04-05 06:07:08");
+    }
+
+    #[test]
+    fn collects_every_date_tag() {
+        let rational = |n| Rational { num: n, denom: 1 };
+        let exif = read(&[
+            ascii(Tag::DateTime, In::PRIMARY, "2023:01:03 10:00:00"),
+            ascii(Tag::SubSecTime, In::PRIMARY, "25"),
+            ascii(Tag::DateTimeDigitized, In::PRIMARY, "2023:01:02 10:00:00"),
+            ascii(Tag::OffsetTimeDigitized, In::PRIMARY, "+09:00"),
+            ascii(Tag::DateTimeOriginal, In::PRIMARY, "0000:00:00 00:00:00"),
+            ascii(Tag::GPSDateStamp, In::PRIMARY, "2023:01:01"),
+            Field {
+                tag: Tag::GPSTimeStamp,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![rational(9), rational(30), rational(15)]),
+            },
+        ]);
+        let utc = TimeZoneSpec::Fixed(FixedOffset::east_opt(0).unwrap());
+        let found: Vec<(String, String)> = exif_candidates(&exif)
+            .into_iter()
+            .map(|c| (c.label.clone(), c.instant(&utc).format("%F %T%.f %:z").to_string()))
+            .collect();
+        let expected = [
+            ("EXIF DateTimeOriginal '0000:00:00 00:00:00'", "0000-01-01 00:00:00 +00:00"),
+            ("EXIF DateTimeDigitized", "2023-01-02 10:00:00 +09:00"),
+            ("EXIF DateTime", "2023-01-03 10:00:00.250 +00:00"),
+            ("EXIF GPSDateStamp (UTC)", "2023-01-01 09:30:15 +00:00"),
+        ];
+        assert_eq!(found.len(), expected.len());
+        for ((label, date), (exp_label, exp_date)) in found.iter().zip(expected) {
+            assert_eq!(label, exp_label);
+            assert_eq!(date, exp_date);
+        }
+    }
+
+    #[test]
+    fn reads_coordinates() {
+        let dms = |d, m, s| {
+            Value::Rational(vec![
+                Rational { num: d, denom: 1 },
+                Rational { num: m, denom: 1 },
+                Rational { num: s, denom: 100 },
+            ])
+        };
+        let field = |tag, value| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        };
+        let exif = read(&[
+            field(Tag::GPSLatitude, dms(33, 52, 1200)),
+            ascii(Tag::GPSLatitudeRef, In::PRIMARY, "S"),
+            field(Tag::GPSLongitude, dms(151, 12, 3600)),
+            ascii(Tag::GPSLongitudeRef, In::PRIMARY, "E"),
+        ]);
+        let (lat, lon) = exif_coordinates(&exif).unwrap();
+        assert!((lat + 33.87).abs() < 1e-6);
+        assert!((lon - 151.21).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,150 @@
+//! Google Photos Takeout metadata, stored in a JSON file next to each photo.
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Takeout cuts JSON names to this many characters before the `(n)` suffix
+/// and the `.json` extension
+const MAX_NAME_CHARS: usize = 46;
+
+/// Infixes between the media name and `.json`, newest exports use the second
+const INFIXES: &[&str] = &["", ".supplemental-metadata"];
+
+#[derive(Debug, Deserialize)]
+struct Timestamp {
+    /// Seconds since the epoch, as a string
+    timestamp: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeoData {
+    latitude: f64,
+    longitude: f64,
+}
+
+/// The parts of a Takeout JSON file used here
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    photo_taken_time: Option<Timestamp>,
+    geo_data: Option<GeoData>,
+}
+
+impl Metadata {
+    /// When the photo was taken
+    pub fn taken(&self) -> Option<DateTime<Utc>> {
+        let seconds = self.photo_taken_time.as_ref()?.timestamp.trim().parse().ok()?;
+        DateTime::from_timestamp(seconds, 0)
+    }
+
+    /// Latitude and longitude, Takeout writes zeros when there are none
+    pub fn coordinates(&self) -> Option<(f64, f64)> {
+        self.geo_data
+            .as_ref()
+            .filter(|g| g.latitude != 0.0 || g.longitude != 0.0)
+            .map(|g| (g.latitude, g.longitude))
+    }
+}
+
+/// Split a `(n)` duplicate counter off a file stem
+fn split_counter(stem: &str) -> (&str, &str) {
+    if let Some(open) = stem.rfind('(')
+        && stem.ends_with(')')
+        && open + 2 < stem.len()
+        && stem[open + 1..stem.len() - 1].bytes().all(|b| b.is_ascii_digit())
+    {
+        return (&stem[..open], &stem[open..]);
+    }
+    (stem, "")
+}
+
+/// Possible JSON names for a media file, most likely first. `IMG(1).jpg`
+/// is described by `IMG.jpg(1).json`, edited copies share the JSON of the
+/// original, and long names are truncated.
+pub fn json_names(file_name: &str) -> Vec<String> {
+    let (stem, extension) = match file_name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (file_name, String::new()),
+    };
+    let (stem, counter) = split_counter(stem);
+    let mut stems = vec![stem];
+    if let Some(original) = stem.strip_suffix("-edited") {
+        stems.push(original);
+    }
+    let mut names = Vec::new();
+    for stem in stems {
+        for infix in INFIXES {
+            let full = format!("{}{}{}", stem, extension, infix);
+            let truncated: String = full.chars().take(MAX_NAME_CHARS).collect();
+            for base in [full, truncated] {
+                names.push(format!("{}{}.json", base, counter));
+            }
+        }
+        // Some exports drop the media extension
+        names.push(format!("{}{}.json", stem, counter));
+    }
+    let mut seen = HashSet::new();
+    names.retain(|n| seen.insert(n.clone()));
+    names
+}
+
+/// Path of the Takeout JSON describing a file, if there is one
+pub fn json_path(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_string_lossy();
+    json_names(&file_name)
+        .into_iter()
+        .map(|name| path.with_file_name(name))
+        .find(|p| p.is_file())
+}
+
+/// Read the Takeout metadata of a file
+pub fn read(path: &Path) -> Result<Metadata, String> {
+    let json = json_path(path).ok_or("No Takeout JSON")?;
+    let text = fs::read_to_string(&json).map_err(|e| e.to_string())?;
+    serde_json::from_str(&text).map_err(|e| format!("Invalid Takeout JSON {}: {}", json.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_json_names() {
+        let names = json_names("IMG_20230101_120000.jpg");
+        assert_eq!(names[0], "IMG_20230101_120000.jpg.json");
+        assert!(names.contains(&"IMG_20230101_120000.jpg.supplemental-metadata.json".to_string()));
+
+        let names = json_names("IMG_20230101_120000(1).jpg");
+        assert_eq!(names[0], "IMG_20230101_120000.jpg(1).json");
+
+        let names = json_names("IMG_20230101_120000-edited.jpg");
+        assert!(names.contains(&"IMG_20230101_120000.jpg.json".to_string()));
+
+        let long = "Screenshot_20230101-123456_Some_Very_Long_App_Name.png";
+        let names = json_names(long);
+        assert!(names.contains(&"Screenshot_20230101-123456_Some_Very_Long_App_.json".to_string()));
+        let supplemental = "PXL_20230101_123456789.MP.jpg.supplemental-met.json";
+        assert!(json_names("PXL_20230101_123456789.MP.jpg").contains(&supplemental.to_string()));
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let text = r#"{
+            "title": "IMG_20230101_120000.jpg",
+            "photoTakenTime": {"timestamp": "1672574400", "formatted": "Jan 1, 2023, 12:00:00 PM UTC"},
+            "geoData": {"latitude": 48.8584, "longitude": 2.2945, "altitude": 0.0},
+            "geoDataExif": {"latitude": 0.0, "longitude": 0.0}
+        }"#;
+        let metadata: Metadata = serde_json::from_str(text).unwrap();
+        assert_eq!(metadata.taken().unwrap().to_rfc3339(), "2023-01-01T12:00:00+00:00");
+        assert_eq!(metadata.coordinates(), Some((48.8584, 2.2945)));
+
+        let text = r#"{"geoData": {"latitude": 0.0, "longitude": 0.0}}"#;
+        let metadata: Metadata = serde_json::from_str(text).unwrap();
+        assert_eq!(metadata.taken(), None);
+        assert_eq!(metadata.coordinates(), None);
+    }
+}
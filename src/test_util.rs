@@ -0,0 +1,68 @@
+//! Helpers shared by the unit tests
+
+use crate::jpeg;
+use exif::{Field, In, Tag, Value};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// An ASCII EXIF field
+pub fn ascii(tag: Tag, ifd: In, value: &str) -> Field {
+    Field {
+        tag,
+        ifd_num: ifd,
+        value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+    }
+}
+
+/// A TIFF block holding the given EXIF fields
+pub fn tiff(fields: &[Field], little_endian: bool) -> Vec<u8> {
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut out = Cursor::new(Vec::new());
+    writer.write(&mut out, little_endian).unwrap();
+    out.into_inner()
+}
+
+/// A JPEG with the given EXIF fields and no image data
+pub fn exif_jpeg(fields: &[Field]) -> Vec<u8> {
+    jpeg::replace_exif_tiff(&[0xFF, 0xD8, 0xFF, 0xD9], &tiff(fields, false)).unwrap()
+}
+
+/// An ISO base media file format box
+pub fn bx(typ: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut out = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+    out.extend_from_slice(typ);
+    out.extend_from_slice(content);
+    out
+}
+
+/// A new empty temporary directory for a test, removed with its contents
+/// when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(test: &str) -> Self {
+        let name = format!("heuristic-dates-{}-{}", test, std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,301 @@
+//! Minimal in-place editing of TIFF structures as found in EXIF blocks.
+//!
+//! Existing data is never moved: values that fit are overwritten where they
+//! are, otherwise the edited IFD is appended to the end of the block and the
+//! pointer to it is updated. Every other tag keeps its bytes and offsets.
+
+use chrono::{DateTime, FixedOffset, Timelike};
+
+/// ASCII field type
+const ASCII: u16 = 2;
+/// LONG field type
+const LONG: u16 = 4;
+/// IFD field type, equivalent to LONG for pointers
+const IFD: u16 = 13;
+
+pub const TAG_DATE_TIME: u16 = 0x0132;
+pub const TAG_EXIF_IFD_POINTER: u16 = 0x8769;
+pub const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+pub const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+pub const TAG_OFFSET_TIME: u16 = 0x9010;
+pub const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+pub const TAG_OFFSET_TIME_DIGITIZED: u16 = 0x9012;
+pub const TAG_SUB_SEC_TIME: u16 = 0x9290;
+pub const TAG_SUB_SEC_TIME_ORIGINAL: u16 = 0x9291;
+pub const TAG_SUB_SEC_TIME_DIGITIZED: u16 = 0x9292;
+
+/// Format used by EXIF date fields
+pub const EXIF_DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+/// The IFDs that can be edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ifd {
+    Primary,
+    Exif,
+}
+
+/// A TIFF block being edited
+#[derive(Debug, Clone)]
+pub struct TiffEditor {
+    data: Vec<u8>,
+    big_endian: bool,
+}
+
+type Entry = [u8; 12];
+
+impl TiffEditor {
+    /// Start editing an existing TIFF block
+    pub fn new(data: Vec<u8>) -> Result<Self, String> {
+        let big_endian = match data.get(0..4) {
+            Some(b"MM\0*") => true,
+            Some(b"II*\0") => false,
+            _ => return Err("Invalid TIFF header".to_string()),
+        };
+        let editor = TiffEditor { data, big_endian };
+        if editor.data.len() < 8 {
+            return Err("Truncated TIFF header".to_string());
+        }
+        Ok(editor)
+    }
+
+    /// A little-endian TIFF block with an empty primary IFD
+    pub fn empty() -> Self {
+        let mut data = b"II*\0".to_vec();
+        data.extend_from_slice(&8u32.to_le_bytes());
+        data.extend_from_slice(&[0; 6]);
+        TiffEditor {
+            data,
+            big_endian: false,
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn u16_at(&self, pos: usize) -> Result<u16, String> {
+        let b: [u8; 2] = self
+            .data
+            .get(pos..pos + 2)
+            .and_then(|s| s.try_into().ok())
+            .ok_or("TIFF offset out of range")?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Result<u32, String> {
+        let b: [u8; 4] = self
+            .data
+            .get(pos..pos + 4)
+            .and_then(|s| s.try_into().ok())
+            .ok_or("TIFF offset out of range")?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        })
+    }
+
+    fn u16_bytes(&self, v: u16) -> [u8; 2] {
+        if self.big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    fn u32_bytes(&self, v: u32) -> [u8; 4] {
+        if self.big_endian {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        }
+    }
+
+    fn entry_tag(&self, entry: &Entry) -> u16 {
+        let b = [entry[0], entry[1]];
+        if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    /// Offset of an IFD, if present
+    fn ifd_offset(&self, ifd: Ifd) -> Result<Option<usize>, String> {
+        let primary = self.u32_at(4)? as usize;
+        match ifd {
+            Ifd::Primary => Ok(Some(primary)),
+            Ifd::Exif => {
+                let (entries, _) = self.read_ifd(primary)?;
+                match entries.iter().position(|e| self.entry_tag(e) == TAG_EXIF_IFD_POINTER) {
+                    Some(i) => Ok(Some(self.u32_at(primary + 2 + i * 12 + 8)? as usize)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    fn read_ifd(&self, offset: usize) -> Result<(Vec<Entry>, u32), String> {
+        let count = self.u16_at(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let pos = offset + 2 + i * 12;
+            let entry: Entry = self
+                .data
+                .get(pos..pos + 12)
+                .and_then(|s| s.try_into().ok())
+                .ok_or("Truncated IFD")?;
+            entries.push(entry);
+        }
+        let next = self.u32_at(offset + 2 + count * 12)?;
+        Ok((entries, next))
+    }
+
+    /// Append bytes at an even offset and return that offset
+    fn append(&mut self, bytes: &[u8]) -> Result<u32, String> {
+        if self.data.len() % 2 == 1 {
+            self.data.push(0);
+        }
+        let offset = u32::try_from(self.data.len()).map_err(|_| "TIFF block too large")?;
+        self.data.extend_from_slice(bytes);
+        Ok(offset)
+    }
+
+    fn append_ifd(&mut self, entries: &[Entry], next: u32) -> Result<u32, String> {
+        let mut bytes = self.u16_bytes(entries.len() as u16).to_vec();
+        for entry in entries {
+            bytes.extend_from_slice(entry);
+        }
+        bytes.extend_from_slice(&self.u32_bytes(next));
+        self.append(&bytes)
+    }
+
+    fn set_ifd_pointer(&mut self, ifd: Ifd, offset: u32) -> Result<(), String> {
+        match ifd {
+            Ifd::Primary => {
+                let bytes = self.u32_bytes(offset);
+                self.data[4..8].copy_from_slice(&bytes);
+                Ok(())
+            }
+            Ifd::Exif => {
+                let bytes = self.u32_bytes(offset);
+                self.set_field(Ifd::Primary, TAG_EXIF_IFD_POINTER, LONG, 1, &bytes)
+            }
+        }
+    }
+
+    /// Set a field, overwriting it in place when the type matches and the new
+    /// value fits in the old one, or rebuilding the IFD otherwise
+    fn set_field(
+        &mut self,
+        ifd: Ifd,
+        tag: u16,
+        typ: u16,
+        count: u32,
+        value: &[u8],
+    ) -> Result<(), String> {
+        let offset = match self.ifd_offset(ifd)? {
+            Some(offset) => offset,
+            None => {
+                let offset = self.append_ifd(&[], 0)?;
+                self.set_ifd_pointer(ifd, offset)?;
+                offset as usize
+            }
+        };
+        let (mut entries, next) = self.read_ifd(offset)?;
+        if let Some(i) = entries.iter().position(|e| self.entry_tag(e) == tag) {
+            let entry_pos = offset + 2 + i * 12;
+            let old_type = self.u16_at(entry_pos + 2)?;
+            let old_count = self.u32_at(entry_pos + 4)?;
+            let same_type = old_type == typ || (typ == LONG && old_type == IFD);
+            if same_type && old_count >= count {
+                // Only ASCII and LONG values are written here
+                let old_size = old_count as usize * if typ == LONG { 4 } else { 1 };
+                let pos = if old_size <= 4 {
+                    entry_pos + 8
+                } else {
+                    self.u32_at(entry_pos + 8)? as usize
+                };
+                let slot = self
+                    .data
+                    .get_mut(pos..pos + old_size)
+                    .ok_or("TIFF value out of range")?;
+                slot.fill(0);
+                slot[..value.len()].copy_from_slice(value);
+                return Ok(());
+            }
+        }
+        let mut entry: Entry = [0; 12];
+        entry[0..2].copy_from_slice(&self.u16_bytes(tag));
+        entry[2..4].copy_from_slice(&self.u16_bytes(typ));
+        entry[4..8].copy_from_slice(&self.u32_bytes(count));
+        if value.len() <= 4 {
+            entry[8..8 + value.len()].copy_from_slice(value);
+        } else {
+            let value_offset = self.append(value)?;
+            entry[8..12].copy_from_slice(&self.u32_bytes(value_offset));
+        }
+        entries.retain(|e| self.entry_tag(e) != tag);
+        entries.push(entry);
+        entries.sort_by_key(|e| self.entry_tag(e));
+        let new_offset = self.append_ifd(&entries, next)?;
+        self.set_ifd_pointer(ifd, new_offset)
+    }
+
+    /// Whether an IFD has a field
+    fn has_field(&self, ifd: Ifd, tag: u16) -> Result<bool, String> {
+        let Some(offset) = self.ifd_offset(ifd)? else {
+            return Ok(false);
+        };
+        let (entries, _) = self.read_ifd(offset)?;
+        Ok(entries.iter().any(|e| self.entry_tag(e) == tag))
+    }
+
+    /// Set an ASCII field
+    pub fn set_ascii(&mut self, ifd: Ifd, tag: u16, value: &str) -> Result<(), String> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.set_field(ifd, tag, ASCII, bytes.len() as u32, &bytes)
+    }
+
+    /// Set DateTimeOriginal, DateTimeDigitized (CreateDate) and DateTime
+    /// (ModifyDate) to the local time of the date, and the matching OffsetTime
+    /// tags to its offset. The SubSecTime tags are set to its fraction of a
+    /// second when they exist or it has one, so old fractions never remain.
+    pub fn set_dates(&mut self, date: DateTime<FixedOffset>) -> Result<(), String> {
+        let formatted = date.format(EXIF_DATE_FORMAT).to_string();
+        let offset = date.format("%:z").to_string();
+        let millis = date.format("%3f").to_string();
+        let fraction = match millis.trim_end_matches('0') {
+            "" => "0",
+            digits => digits,
+        };
+        self.set_ascii(Ifd::Exif, TAG_DATE_TIME_ORIGINAL, &formatted)?;
+        self.set_ascii(Ifd::Exif, TAG_DATE_TIME_DIGITIZED, &formatted)?;
+        self.set_ascii(Ifd::Primary, TAG_DATE_TIME, &formatted)?;
+        self.set_ascii(Ifd::Exif, TAG_OFFSET_TIME_ORIGINAL, &offset)?;
+        self.set_ascii(Ifd::Exif, TAG_OFFSET_TIME_DIGITIZED, &offset)?;
+        self.set_ascii(Ifd::Exif, TAG_OFFSET_TIME, &offset)?;
+        for tag in [TAG_SUB_SEC_TIME_ORIGINAL, TAG_SUB_SEC_TIME_DIGITIZED, TAG_SUB_SEC_TIME] {
+            if date.nanosecond() != 0 || self.has_field(Ifd::Exif, tag)? {
+                self.set_ascii(Ifd::Exif, tag, fraction)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the dates in an existing TIFF block, or in a new one when there is
+/// none, and return the resulting block
+pub fn with_dates(block: Option<&[u8]>, date: DateTime<FixedOffset>) -> Result<Vec<u8>, String> {
+    let mut editor = match block {
+        Some(block) => TiffEditor::new(block.to_vec())?,
+        None => TiffEditor::empty(),
+    };
+    editor.set_dates(date)?;
+    Ok(editor.into_bytes())
+}
@@ -0,0 +1,150 @@
+//! Time zone used to interpret local dates that carry no offset.
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tzf_rs::DefaultFinder;
+
+/// A time zone given on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TimeZoneSpec {
+    /// The time zone of the system running the tool
+    #[default]
+    Local,
+    /// A fixed offset from UTC, e.g. `+02:00`
+    Fixed(FixedOffset),
+    /// An IANA time zone, e.g. `Europe/Berlin`
+    Named(Tz),
+}
+
+/// Parse an offset such as `+02:00`, `-0530`, `+09` or `Z`
+pub fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let s = s.trim();
+    if s == "Z" {
+        return FixedOffset::east_opt(0);
+    }
+    let (sign, rest) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let digits: String = rest.chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+        _ => return None,
+    };
+    if minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+impl FromStr for TimeZoneSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("local") {
+            return Ok(TimeZoneSpec::Local);
+        }
+        if let Some(offset) = parse_offset(s) {
+            return Ok(TimeZoneSpec::Fixed(offset));
+        }
+        s.parse::<Tz>()
+            .map(TimeZoneSpec::Named)
+            .map_err(|_| format!("Unknown time zone: {}", s))
+    }
+}
+
+impl fmt::Display for TimeZoneSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeZoneSpec::Local => write!(f, "local"),
+            TimeZoneSpec::Fixed(offset) => write!(f, "{}", offset),
+            TimeZoneSpec::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+/// Offset of a time zone at a local time. Ambiguous times take the earlier
+/// offset and times skipped by a transition take the offset before it.
+fn offset_of<T: TimeZone>(tz: &T, naive: NaiveDateTime) -> FixedOffset {
+    match tz.offset_from_local_datetime(&naive).earliest() {
+        Some(offset) => offset.fix(),
+        None => tz.offset_from_utc_datetime(&naive).fix(),
+    }
+}
+
+impl TimeZoneSpec {
+    /// Offset from UTC at the given local time
+    pub fn offset_at(&self, naive: NaiveDateTime) -> FixedOffset {
+        match self {
+            TimeZoneSpec::Local => offset_of(&Local, naive),
+            TimeZoneSpec::Fixed(offset) => *offset,
+            TimeZoneSpec::Named(tz) => offset_of(tz, naive),
+        }
+    }
+
+    /// Attach the offset of this time zone to a local time
+    pub fn localize(&self, naive: NaiveDateTime) -> DateTime<FixedOffset> {
+        with_offset(naive, self.offset_at(naive))
+    }
+}
+
+/// Attach a fixed offset to a local time
+pub fn with_offset(naive: NaiveDateTime, offset: FixedOffset) -> DateTime<FixedOffset> {
+    DateTime::from_naive_utc_and_offset(naive - offset, offset)
+}
+
+/// Time zone at a position, looked up in the embedded boundary dataset
+pub fn zone_at(latitude: f64, longitude: f64) -> Option<Tz> {
+    static FINDER: OnceLock<DefaultFinder> = OnceLock::new();
+    let finder = FINDER.get_or_init(DefaultFinder::new);
+    finder.get_tz_name(longitude, latitude).parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, NaiveDate};
+
+    #[test]
+    fn parses_specs() {
+        let hours = |h| TimeZoneSpec::Fixed(FixedOffset::east_opt(h * 3600).unwrap());
+        assert_eq!("+02:00".parse::<TimeZoneSpec>(), Ok(hours(2)));
+        assert_eq!("-0300".parse::<TimeZoneSpec>(), Ok(hours(-3)));
+        assert_eq!("+09".parse::<TimeZoneSpec>(), Ok(hours(9)));
+        assert_eq!(
+            "Europe/Berlin".parse::<TimeZoneSpec>(),
+            Ok(TimeZoneSpec::Named(chrono_tz::Europe::Berlin))
+        );
+        assert_eq!("local".parse::<TimeZoneSpec>(), Ok(TimeZoneSpec::Local));
+        assert!("Mars/Olympus".parse::<TimeZoneSpec>().is_err());
+    }
+
+    #[test]
+    fn localizes_with_dst() {
+        let tz: TimeZoneSpec = "Europe/Berlin".parse().unwrap();
+        let summer = NaiveDate::from_ymd_opt(2023, 7, 1)
+            .unwrap()
+            .and_hms_opt(14, 0, 0)
+            .unwrap();
+        let dt = tz.localize(summer);
+        assert_eq!(dt.naive_local(), summer);
+        assert_eq!(dt.naive_utc().format("%H:%M").to_string(), "12:00");
+        let winter = summer.with_month(1).unwrap();
+        assert_eq!(tz.localize(winter).naive_utc().format("%H:%M").to_string(), "13:00");
+    }
+
+    #[test]
+    fn finds_zone_from_coordinates() {
+        assert_eq!(zone_at(52.52, 13.405), Some(chrono_tz::Europe::Berlin));
+        assert_eq!(zone_at(35.68, 139.69), Some(chrono_tz::Asia::Tokyo));
+        assert_eq!(zone_at(-33.87, 151.21), Some(chrono_tz::Australia::Sydney));
+    }
+}
@@ -0,0 +1,184 @@
+//! Dates in WebP files, stored in the `EXIF` chunk of the RIFF container.
+
+use crate::tiff;
+use chrono::{DateTime, FixedOffset};
+use std::ops::Range;
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// VP8X flag telling that an EXIF chunk is present
+const EXIF_FLAG: u8 = 0x08;
+
+struct Chunk {
+    fourcc: [u8; 4],
+    /// The whole chunk including its padding byte
+    range: Range<usize>,
+    data: Range<usize>,
+}
+
+fn chunks(data: &[u8]) -> Result<Vec<Chunk>, String> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("Not a WebP file".to_string());
+    }
+    let mut out = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let fourcc: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let data_end = pos + 8 + len;
+        if data_end > data.len() {
+            return Err("Truncated WebP chunk".to_string());
+        }
+        let end = (data_end + len % 2).min(data.len());
+        out.push(Chunk {
+            fourcc,
+            range: pos..end,
+            data: pos + 8..data_end,
+        });
+        pos = end;
+    }
+    Ok(out)
+}
+
+fn make_chunk(fourcc: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut out = fourcc.to_vec();
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+    out
+}
+
+/// Canvas size of a simple (VP8 or VP8L) WebP image
+fn canvas_size(data: &[u8], chunk: &Chunk) -> Option<(u32, u32)> {
+    let body = &data[chunk.data.clone()];
+    match &chunk.fourcc {
+        b"VP8 " => {
+            if body.get(3..6)? != [0x9d, 0x01, 0x2a] {
+                return None;
+            }
+            let w = u16::from_le_bytes(body.get(6..8)?.try_into().ok()?) & 0x3FFF;
+            let h = u16::from_le_bytes(body.get(8..10)?.try_into().ok()?) & 0x3FFF;
+            Some((w as u32, h as u32))
+        }
+        b"VP8L" => {
+            if *body.first()? != 0x2f {
+                return None;
+            }
+            let bits = u32::from_le_bytes(body.get(1..5)?.try_into().ok()?);
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        _ => None,
+    }
+}
+
+/// The `VP8X` header of a canvas, None for an empty one
+fn vp8x(width: u32, height: u32, flags: u8) -> Option<Vec<u8>> {
+    let mut body = vec![flags, 0, 0, 0];
+    body.extend_from_slice(&width.checked_sub(1)?.to_le_bytes()[..3]);
+    body.extend_from_slice(&height.checked_sub(1)?.to_le_bytes()[..3]);
+    Some(make_chunk(b"VP8X", &body))
+}
+
+/// Set the EXIF dates, adding the `EXIF` chunk (and a `VP8X` header for
+/// simple images) when missing
+pub fn write_dates(data: &[u8], date: DateTime<FixedOffset>) -> Result<Vec<u8>, String> {
+    let chunks = chunks(data)?;
+    let exif = chunks.iter().find(|c| &c.fourcc == b"EXIF");
+    let (prefix, old) = match exif {
+        Some(c) => {
+            let body = &data[c.data.clone()];
+            match body.strip_prefix(EXIF_HEADER) {
+                Some(tiff) => (EXIF_HEADER, Some(tiff)),
+                None => (&b""[..], Some(body)),
+            }
+        }
+        None => (&b""[..], None),
+    };
+    let mut payload = prefix.to_vec();
+    payload.extend_from_slice(&tiff::with_dates(old, date)?);
+
+    let mut out = data[..12].to_vec();
+    if chunks.first().map(|c| &c.fourcc) != Some(b"VP8X") {
+        let image = chunks.first().ok_or("WebP file has no image")?;
+        let header = canvas_size(data, image)
+            .and_then(|(width, height)| vp8x(width, height, EXIF_FLAG))
+            .ok_or("Unsupported WebP image")?;
+        out.extend_from_slice(&header);
+    }
+    for chunk in &chunks {
+        match &chunk.fourcc {
+            b"EXIF" => continue,
+            b"VP8X" => {
+                if chunk.data.is_empty() {
+                    return Err("Invalid VP8X chunk".to_string());
+                }
+                let mut header = data[chunk.range.clone()].to_vec();
+                header[8] |= EXIF_FLAG;
+                out.extend_from_slice(&header);
+            }
+            _ => out.extend_from_slice(&data[chunk.range.clone()]),
+        }
+    }
+    // Metadata chunks go after the image data
+    out.extend_from_slice(&make_chunk(b"EXIF", &payload));
+    let riff_size = u32::try_from(out.len() - 8).map_err(|_| "WebP file too large")?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{In, Reader, Tag};
+    use std::io::Cursor;
+
+    /// A lossless 3x2 image header; the pixel data does not matter here
+    fn webp() -> Vec<u8> {
+        let bits: u32 = 2 | (1 << 14);
+        let mut vp8l = vec![0x2f];
+        vp8l.extend_from_slice(&bits.to_le_bytes());
+        let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+        data.extend_from_slice(&make_chunk(b"VP8L", &vp8l));
+        let size = (data.len() - 8) as u32;
+        data[4..8].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn adds_exif_to_simple_image() {
+        let date = DateTime::parse_from_rfc3339("2020-02-03T04:05:06+01:00").unwrap();
+        let updated = write_dates(&webp(), date).unwrap();
+        let found = chunks(&updated).unwrap();
+        let fourccs: Vec<&[u8; 4]> = found.iter().map(|c| &c.fourcc).collect();
+        assert_eq!(fourccs, [b"VP8X", b"VP8L", b"EXIF"]);
+        assert_eq!(canvas_size(&webp(), &chunks(&webp()).unwrap()[0]), Some((3, 2)));
+        assert_eq!(updated[20] & EXIF_FLAG, EXIF_FLAG);
+        assert_eq!(&updated[24..30], &[2, 0, 0, 1, 0, 0]);
+        // Writing again replaces the chunk
+        let again = write_dates(&updated, date).unwrap();
+        assert_eq!(again.len(), updated.len());
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(&again))
+            .unwrap();
+        let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+        assert_eq!(field.display_value().to_string(), "2020-02-03 04:05:06");
+    }
+
+    #[test]
+    fn rejects_truncated_and_empty_images() {
+        let date = DateTime::parse_from_rfc3339("2020-02-03T04:05:06+01:00").unwrap();
+        let image = |fourcc, body: &[u8]| {
+            let mut data = b"RIFF\0\0\0\0WEBP".to_vec();
+            data.extend_from_slice(&make_chunk(fourcc, body));
+            data
+        };
+        let lossy = |body: &[u8]| image(b"VP8 ", body);
+        let header = image(b"VP8X", &[]);
+        assert_eq!(write_dates(&header, date), Err("Invalid VP8X chunk".to_string()));
+        assert!(write_dates(&lossy(&[0, 0, 0, 0x9d, 0x01, 0x2a]), date).is_err());
+        let empty = lossy(&[0, 0, 0, 0x9d, 0x01, 0x2a, 0, 0, 2, 0]);
+        assert_eq!(canvas_size(&empty, &chunks(&empty).unwrap()[0]), Some((0, 2)));
+        assert!(write_dates(&empty, date).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::xmp::{self, SidecarNaming};
+use crate::{heif, jpeg, mp4, png, webp};
+use chrono::{DateTime, FixedOffset};
+use filetime::{FileTime, set_file_times};
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Applies a resolved date to a file
+pub trait Writer: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// Whether this writer can handle the file
+    fn supports(&self, path: &Path) -> bool;
+
+    /// Write a date. Formats without time zone support receive its local time.
+    fn write(&self, path: &Path, date: DateTime<FixedOffset>) -> Result<(), String>;
+}
+
+/// Check the file extension against a list, ignoring case
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| extensions.contains(&e.as_str()))
+}
+
+/// Number of files per extension that a writer could not handle
+#[derive(Default)]
+pub struct Unsupported {
+    counts: Mutex<BTreeMap<String, usize>>,
+}
+
+impl Unsupported {
+    pub fn add(&self, path: &Path) {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        *self.counts.lock().unwrap().entry(ext).or_default() += 1;
+    }
+
+    /// The extensions with their number of files, None when there are none
+    pub fn summary(&self) -> Option<String> {
+        let counts = self.counts.lock().unwrap();
+        if counts.is_empty() {
+            return None;
+        }
+        let summary: Vec<String> = counts
+            .iter()
+            .map(|(ext, count)| format!("{} ({} file(s))", ext, count))
+            .collect();
+        Some(summary.join(", "))
+    }
+}
+
+/// Replace the contents of a file through a temporary file in the same
+/// directory, so an interrupted write never leaves a truncated file behind
+pub fn replace_contents(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut tmp_name = path.file_name().ok_or("Invalid file name")?.to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    let permissions = fs::metadata(path).map_err(|e| e.to_string())?.permissions();
+    fs::write(&tmp, data).map_err(|e| e.to_string())?;
+    fs::set_permissions(&tmp, permissions).map_err(|e| e.to_string())?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e.to_string()
+    })
+}
+
+/// Writes dates in-process: EXIF DateTimeOriginal, CreateDate and ModifyDate
+/// of JPEG, HEIF, PNG and WebP files, leaving every other tag untouched, and
+/// the container dates of MP4 and QuickTime files, in place. RAW files are
+/// left alone and get an XMP sidecar instead; existing sidecars of other
+/// files are kept in sync.
+#[derive(Default)]
+pub struct NativeWriter {
+    sidecar: XmpSidecarWriter,
+}
+
+impl NativeWriter {
+    pub fn new(sidecar_naming: SidecarNaming) -> Self {
+        NativeWriter {
+            sidecar: XmpSidecarWriter::new(sidecar_naming),
+        }
+    }
+
+    /// TIFF-based RAW formats
+    pub const RAW: &'static [&'static str] = &["dng", "cr2", "nef", "arw"];
+    const JPEG: &'static [&'static str] = &["jpg", "jpeg"];
+    const HEIF: &'static [&'static str] = &["heic", "heif"];
+    const PNG: &'static [&'static str] = &["png"];
+    const WEBP: &'static [&'static str] = &["webp"];
+    const MP4: &'static [&'static str] = &["mp4", "mov", "3gp", "m4v"];
+
+    /// Write the dates into the file itself
+    fn write_file(&self, path: &Path, date: DateTime<FixedOffset>) -> Result<(), String> {
+        if has_extension(path, Self::MP4) {
+            let mut fh = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .map_err(|e| e.to_string())?;
+            for label in mp4::write_dates(&mut fh, date)? {
+                warn!("Could not update {} of {} in place", label, path.display());
+            }
+            return Ok(());
+        }
+        let data = fs::read(path).map_err(|e| e.to_string())?;
+        let updated = if has_extension(path, Self::HEIF) {
+            heif::write_dates(&data, date)?
+        } else if has_extension(path, Self::PNG) {
+            png::write_dates(&data, date)?
+        } else if has_extension(path, Self::WEBP) {
+            webp::write_dates(&data, date)?
+        } else {
+            jpeg::write_dates(&data, date)?
+        };
+        replace_contents(path, &updated)
+    }
+}
+
+impl Writer for NativeWriter {
+    fn name(&self) -> &str {
+        "native"
+    }
+
+    fn supports(&self, path: &Path) -> bool {
+        [Self::RAW, Self::JPEG, Self::HEIF, Self::PNG, Self::WEBP, Self::MP4]
+            .iter()
+            .any(|exts| has_extension(path, exts))
+    }
+
+    fn write(&self, path: &Path, date: DateTime<FixedOffset>) -> Result<(), String> {
+        if has_extension(path, Self::RAW) {
+            return self.sidecar.write(path, date);
+        }
+        self.write_file(path, date)?;
+        if xmp::sidecar_path(path, self.sidecar.naming).exists() {
+            self.sidecar.write(path, date)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes EXIF dates by running exiftool
+pub struct ExiftoolWriter;
+
+impl ExiftoolWriter {
+    /// Formats exiftool can write dates to
+    const EXTENSIONS: &'static [&'static str] = &[
+        "jpg", "jpeg", "heic", "heif", "png", "webp", "tif", "tiff", "dng", "cr2", "nef", "arw",
+        "mp4", "mov", "3gp",
+    ];
+
+    /// Check that exiftool can be run
+    pub fn detect() -> Result<Self, String> {
+        match Command::new("exiftool").arg("-ver").output() {
+            Ok(out) if out.status.success() => Ok(ExiftoolWriter),
+            Ok(out) => Err(format!("exiftool failed with status: {}", out.status)),
+            Err(e) => Err(format!("exiftool is not available: {}", e)),
+        }
+    }
+}
+
+impl Writer for ExiftoolWriter {
+    fn name(&self) -> &str {
+        "exiftool"
+    }
+
+    fn supports(&self, path: &Path) -> bool {
+        has_extension(path, Self::EXTENSIONS)
+    }
+
+    fn write(&self, path: &Path, date: DateTime<FixedOffset>) -> Result<(), String> {
+        let formatted = date.format("%Y:%m:%d %H:%M:%S").to_string();
+        let offset = date.format("%:z").to_string();
+        let status = Command::new("exiftool")
+            .arg("-DateTimeOriginal=".to_owned() + &formatted)
+            .arg("-CreateDate=".to_owned() + &formatted)
+            .arg("-ModifyDate=".to_owned() + &formatted)
+            .arg("-OffsetTimeOriginal=".to_owned() + &offset)
+            .arg(path)
+            .status();
+        match status {
+            Ok(s) if s.success() => Ok(()),
+            Ok(s) => Err(format!("exiftool failed with status: {}", s)),
+            Err(e) => Err(format!("Failed to run exiftool: {}", e)),
+        }
+    }
+}
+
+/// Writes the date to an XMP sidecar next to the file, leaving the file
+/// itself untouched. An existing sidecar is updated, keeping its other
+/// properties.
+#[derive(Default)]
+pub struct XmpSidecarWriter {
+    naming: SidecarNaming,
+}
+
+impl XmpSidecarWriter {
+    pub fn new(naming: SidecarNaming) -> Self {
+        XmpSidecarWriter { naming }
+    }
+}
+
+impl Writer for XmpSidecarWriter {
+    fn name(&self) -> &str {
+        "xmp-sidecar"
+    }
+
+    fn supports(&self, _path: &Path) -> bool {
+        true
+    }
+
+    fn write(&self, path: &Path, date: DateTime<FixedOffset>) -> Result<(), String> {
+        let sidecar = xmp::sidecar_path(path, self.naming);
+        if sidecar.exists() {
+            let text = fs::read_to_string(&sidecar).map_err(|e| e.to_string())?;
+            return replace_contents(&sidecar, xmp::merge(&text, date)?.as_bytes());
+        }
+        fs::write(&sidecar, xmp::render(date)).map_err(|e| e.to_string())
+    }
+}
+
+/// Sets the modification time of the file, keeping the access time
+pub struct FileTimeWriter;
+
+impl Writer for FileTimeWriter {
+    fn name(&self) -> &str {
+        "filesystem"
+    }
+
+    fn supports(&self, _path: &Path) -> bool {
+        true
+    }
+
+    fn write(&self, path: &Path, date: DateTime<FixedOffset>) -> Result<(), String> {
+        let ft = FileTime::from_unix_time(date.timestamp(), date.timestamp_subsec_nanos());
+        let meta = fs::metadata(path).map_err(|e| e.to_string())?;
+        let atime = FileTime::from_last_access_time(&meta);
+        set_file_times(path, atime, ft).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{TempDir, ascii, exif_jpeg};
+    use exif::{In, Tag};
+
+    fn date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2021-06-05T04:03:02+02:00").unwrap()
+    }
+
+    fn sidecar_dates(path: &Path) -> Vec<String> {
+        let text = fs::read_to_string(path).unwrap();
+        xmp::read_dates(&text)
+            .iter()
+            .map(|(property, dt, _)| format!("{} {}", property, dt))
+            .collect()
+    }
+
+    #[test]
+    fn selects_backends_by_extension() {
+        let native = NativeWriter::default();
+        for name in ["a.jpg", "a.JPEG", "a.heic", "a.png", "a.webp", "a.mov", "a.NEF"] {
+            assert!(native.supports(Path::new(name)), "{}", name);
+        }
+        for name in ["a.gif", "a.tif", "a.txt", "jpg"] {
+            assert!(!native.supports(Path::new(name)), "{}", name);
+        }
+        let exiftool = ExiftoolWriter;
+        assert!(exiftool.supports(Path::new("a.TIF")));
+        assert!(!exiftool.supports(Path::new("a.gif")));
+        let sidecar = XmpSidecarWriter::default();
+        assert!(sidecar.supports(Path::new("a.gif")));
+        assert!(FileTimeWriter.supports(Path::new("a.gif")));
+        let names = [native.name(), exiftool.name(), sidecar.name()];
+        assert_eq!(names, ["native", "exiftool", "xmp-sidecar"]);
+    }
+
+    #[test]
+    fn native_writer_leaves_raw_files_to_sidecars() {
+        let dir = TempDir::new("writer-raw");
+        let raw = dir.join("DSC_0001.NEF");
+        fs::write(&raw, b"raw data").unwrap();
+        let photo = dir.join("DSC_0002.JPG");
+        let original = ascii(Tag::DateTimeOriginal, In::PRIMARY, "2019:08:02 10:00:00");
+        fs::write(&photo, exif_jpeg(&[original])).unwrap();
+        let photo_sidecar = xmp::sidecar_path(&photo, SidecarNaming::Append);
+        fs::write(&photo_sidecar, xmp::render(date() - chrono::Duration::days(1))).unwrap();
+
+        let writer = NativeWriter::new(SidecarNaming::Append);
+        writer.write(&raw, date()).unwrap();
+        writer.write(&photo, date()).unwrap();
+        let raw_data = fs::read(&raw).unwrap();
+        let raw_sidecar = sidecar_dates(&xmp::sidecar_path(&raw, SidecarNaming::Append));
+        let photo_sidecar = sidecar_dates(&photo_sidecar);
+        let exif = crate::source::read_exif(&photo).unwrap();
+
+        assert_eq!(raw_data, b"raw data");
+        assert_eq!(raw_sidecar.len(), 3);
+        assert!(raw_sidecar.iter().all(|d| d.ends_with("2021-06-05 04:03:02")));
+        // The sidecar of a written file is kept in agreement with it
+        assert_eq!(photo_sidecar, raw_sidecar);
+        let original = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).unwrap();
+        assert_eq!(original.display_value().to_string(), "2021-06-05 04:03:02");
+    }
+
+    #[test]
+    fn sidecar_writer_creates_or_merges() {
+        let dir = TempDir::new("writer-sidecar");
+        let path = dir.join("IMG_0001.JPG");
+        fs::write(&path, b"photo").unwrap();
+        let writer = XmpSidecarWriter::new(SidecarNaming::Replace);
+        let sidecar = dir.join("IMG_0001.xmp");
+
+        writer.write(&path, date()).unwrap();
+        let created = fs::read_to_string(&sidecar).unwrap();
+        let existing = created.replace("<rdf:Description", "<rdf:Description xmp:Rating=\"3\"");
+        fs::write(&sidecar, existing).unwrap();
+        writer.write(&path, date() + chrono::Duration::hours(1)).unwrap();
+        let merged = fs::read_to_string(&sidecar).unwrap();
+        let dates = sidecar_dates(&sidecar);
+        let photo = fs::read(&path).unwrap();
+
+        assert_eq!(created, xmp::render(date()));
+        assert!(merged.contains("xmp:Rating=\"3\""));
+        assert_eq!(dates.len(), 3);
+        assert!(dates.iter().all(|d| d.ends_with("2021-06-05 05:03:02")));
+        assert_eq!(photo, b"photo");
+    }
+
+    #[test]
+    fn replaces_contents_keeping_permissions() {
+        let dir = TempDir::new("writer-replace");
+        let path = dir.join("a.jpg");
+        fs::write(&path, b"old").unwrap();
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions).unwrap();
+
+        replace_contents(&path, b"new").unwrap();
+        let data = fs::read(&path).unwrap();
+        let readonly = fs::metadata(&path).unwrap().permissions().readonly();
+        let files = fs::read_dir(dir.path()).unwrap().count();
+        let missing = replace_contents(&dir.join("b.jpg"), b"new");
+
+        assert_eq!(data, b"new");
+        assert!(readonly);
+        // No temporary file is left behind
+        assert_eq!(files, 1);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn summarizes_unsupported_files() {
+        let unsupported = Unsupported::default();
+        assert_eq!(unsupported.summary(), None);
+        for name in ["a.GIF", "b.gif", "c.tif", "d"] {
+            unsupported.add(Path::new(name));
+        }
+        let summary = unsupported.summary().unwrap();
+        assert_eq!(summary, " (1 file(s)), gif (2 file(s)), tif (1 file(s))");
+    }
+}
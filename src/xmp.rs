@@ -0,0 +1,262 @@
+//! XMP sidecar files.
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
+use regex::Regex;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Format used by XMP date properties
+pub const XMP_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%:z";
+
+/// Date properties in order of preference, as (prefix, name, namespace)
+pub const DATE_PROPERTIES: &[(&str, &str, &str)] = &[
+    ("exif", "DateTimeOriginal", "http://ns.adobe.com/exif/1.0/"),
+    ("xmp", "CreateDate", "http://ns.adobe.com/xap/1.0/"),
+    ("photoshop", "DateCreated", "http://ns.adobe.com/photoshop/1.0/"),
+];
+
+/// How sidecar files are named
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SidecarNaming {
+    /// `.xmp` appended to the full name, e.g. `DSC_0001.NEF.xmp`
+    #[default]
+    Append,
+    /// The extension replaced by `.xmp`, e.g. `DSC_0001.xmp`
+    Replace,
+}
+
+impl FromStr for SidecarNaming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "append" => Ok(SidecarNaming::Append),
+            "replace" => Ok(SidecarNaming::Replace),
+            _ => Err(format!(
+                "Invalid sidecar naming '{}', expected 'append' or 'replace'",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SidecarNaming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SidecarNaming::Append => write!(f, "append"),
+            SidecarNaming::Replace => write!(f, "replace"),
+        }
+    }
+}
+
+/// Path of the sidecar for a file
+pub fn sidecar_path(path: &Path, naming: SidecarNaming) -> PathBuf {
+    match naming {
+        SidecarNaming::Append => {
+            let mut name = path.file_name().unwrap_or_default().to_os_string();
+            name.push(".xmp");
+            path.with_file_name(name)
+        }
+        SidecarNaming::Replace => path.with_extension("xmp"),
+    }
+}
+
+/// Parse an XMP date, which may omit the offset, the seconds or the time
+pub fn parse_date(text: &str) -> Option<(NaiveDateTime, Option<FixedOffset>)> {
+    let text = text.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(text) {
+        return Some((dt.naive_local(), Some(*dt.offset())));
+    }
+    if let Ok(dt) = DateTime::parse_from_str(text, "%Y-%m-%dT%H:%M%#z") {
+        return Some((dt.naive_local(), Some(*dt.offset())));
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%dT%H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(text, format) {
+            return Some((dt, None));
+        }
+    }
+    let date = NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()?;
+    Some((date.and_hms_opt(0, 0, 0)?, None))
+}
+
+/// Attribute form of a property, capturing everything before the value
+fn attribute_regex(prefix: &str, name: &str) -> Regex {
+    Regex::new(&format!(r#"(\s{}:{}\s*=\s*)(?:"([^"]*)"|'([^']*)')"#, prefix, name))
+        .expect("valid property regex")
+}
+
+/// Element form of a property, capturing the tags around the value
+fn element_regex(prefix: &str, name: &str) -> Regex {
+    Regex::new(&format!(r"(<{0}:{1}(?:\s[^>]*)?>)([^<]*)(</{0}:{1}>)", prefix, name))
+        .expect("valid property regex")
+}
+
+/// The date properties found in an XMP packet, as (property, date, offset)
+pub fn read_dates(text: &str) -> Vec<(String, NaiveDateTime, Option<FixedOffset>)> {
+    let mut dates = Vec::new();
+    for (prefix, name, _) in DATE_PROPERTIES {
+        let value = attribute_regex(prefix, name)
+            .captures(text)
+            .and_then(|c| c.get(2).or(c.get(3)))
+            .or_else(|| element_regex(prefix, name).captures(text).and_then(|c| c.get(2)));
+        if let Some((dt, offset)) = value.and_then(|v| parse_date(v.as_str())) {
+            dates.push((format!("{}:{}", prefix, name), dt, offset));
+        }
+    }
+    dates
+}
+
+/// Set the date properties in an existing XMP packet. Properties already
+/// present are updated where they are, missing ones are added to the first
+/// rdf:Description, and everything else is left as is.
+pub fn merge(text: &str, date: DateTime<FixedOffset>) -> Result<String, String> {
+    let value = date.format(XMP_DATE_FORMAT).to_string();
+    let mut text = text.to_string();
+    let description = Regex::new(r"<rdf:Description\b[^>]*?(/?>)").expect("valid regex");
+    if !description.is_match(&text) {
+        let end = text.find("</rdf:RDF>").ok_or("No rdf:RDF element in sidecar")?;
+        text.insert_str(end, "<rdf:Description rdf:about=\"\"/>\n");
+    }
+    for (prefix, name, namespace) in DATE_PROPERTIES {
+        let attribute = attribute_regex(prefix, name);
+        let element = element_regex(prefix, name);
+        if attribute.is_match(&text) {
+            text = attribute
+                .replace_all(&text, |c: &regex::Captures| format!("{}\"{}\"", &c[1], value))
+                .into_owned();
+        } else if element.is_match(&text) {
+            text = element
+                .replace_all(&text, |c: &regex::Captures| format!("{}{}{}", &c[1], value, &c[3]))
+                .into_owned();
+        } else {
+            let mut insert = String::new();
+            if !text.contains(&format!("xmlns:{}=", prefix)) {
+                insert.push_str(&format!("\n    xmlns:{}=\"{}\"", prefix, namespace));
+            }
+            insert.push_str(&format!("\n   {}:{}=\"{}\"", prefix, name, value));
+            let end = description
+                .captures(&text)
+                .and_then(|c| c.get(1))
+                .map(|m| m.start())
+                .ok_or("No rdf:Description element in sidecar")?;
+            text.insert_str(end, &insert);
+        }
+    }
+    Ok(text)
+}
+
+/// A new XMP packet carrying the capture date
+pub fn render(date: DateTime<FixedOffset>) -> String {
+    let formatted = date.format(XMP_DATE_FORMAT);
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/"
+   exif:DateTimeOriginal="{0}"
+   xmp:CreateDate="{0}"
+   photoshop:DateCreated="{0}"/>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+        formatted
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_sidecars() {
+        let path = Path::new("raw/DSC_0001.NEF");
+        assert_eq!(
+            sidecar_path(path, SidecarNaming::Append),
+            Path::new("raw/DSC_0001.NEF.xmp")
+        );
+        assert_eq!(
+            sidecar_path(path, SidecarNaming::Replace),
+            Path::new("raw/DSC_0001.xmp")
+        );
+        assert_eq!("replace".parse(), Ok(SidecarNaming::Replace));
+        assert!("sideways".parse::<SidecarNaming>().is_err());
+    }
+
+    fn date() -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339("2021-06-05T04:03:02+02:00").unwrap()
+    }
+
+    fn expected() -> Vec<(String, NaiveDateTime, Option<FixedOffset>)> {
+        let d = date();
+        DATE_PROPERTIES
+            .iter()
+            .map(|(p, n, _)| (format!("{}:{}", p, n), d.naive_local(), Some(*d.offset())))
+            .collect()
+    }
+
+    #[test]
+    fn reads_rendered_packet() {
+        assert_eq!(read_dates(&render(date())), expected());
+    }
+
+    #[test]
+    fn reads_attribute_and_element_forms() {
+        let text = r#"<rdf:Description rdf:about="" xmp:CreateDate='2020-01-02T03:04'>
+   <exif:DateTimeOriginal>2020-01-02T03:04:05.5Z</exif:DateTimeOriginal>
+   <photoshop:DateCreated>2020-01-02</photoshop:DateCreated>
+  </rdf:Description>"#;
+        let found: Vec<String> = read_dates(text)
+            .iter()
+            .map(|(p, dt, offset)| format!("{} {} {:?}", p, dt, offset.map(|o| o.to_string())))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                "exif:DateTimeOriginal 2020-01-02 03:04:05.500 Some(\"+00:00\")",
+                "xmp:CreateDate 2020-01-02 03:04:00 None",
+                "photoshop:DateCreated 2020-01-02 00:00:00 None",
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_into_existing_sidecar() {
+        // As written by darktable: attributes, a child element, other namespaces
+        let text = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:darktable="http://darktable.sf.net/"
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+   xmp:Rating="3"
+   darktable:xmp_version="5">
+   <exif:DateTimeOriginal>2022-01-01T00:00:00</exif:DateTimeOriginal>
+   <darktable:history>
+    <rdf:Seq/>
+   </darktable:history>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#;
+        let merged = merge(text, date()).unwrap();
+        assert_eq!(read_dates(&merged), expected());
+        for kept in [
+            "xmp:Rating=\"3\"",
+            "darktable:xmp_version=\"5\"",
+            "<darktable:history>",
+            "xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"",
+        ] {
+            assert!(merged.contains(kept), "{}", kept);
+        }
+        assert_eq!(merged.matches("xmlns:exif=").count(), 1);
+        // Merging again only rewrites the values
+        assert_eq!(merge(&merged, date()).unwrap(), merged);
+    }
+}